@@ -0,0 +1,205 @@
+//! Finding where resonators start to bifurcate as the drive power goes up
+
+use ndarray::{s, ArrayView1};
+use num_complex::Complex;
+
+use crate::PowerSweepValues;
+
+/// A velocity spike this many times bigger than its neighbours is a jump, not a resonance
+const JUMP_RATIO: f64 = 3.;
+/// How far above the typical velocity a spike needs to be before it means anything
+const PROMINENCE: f64 = 10.;
+/// Ratio between the steepest |S21| slopes either side of the dip that counts as lopsided,
+/// a backstop for coarse sweeps where the jump is split over a couple of steps
+const ASYMMETRY: f64 = 15.;
+
+/// Distance moved in the IQ plane between each pair of consecutive sweep steps
+pub fn iq_velocity(row: ArrayView1<'_, Complex<f32>>) -> Vec<f64> {
+    row.iter()
+        .zip(row.iter().skip(1))
+        .map(|(a, b)| (b - a).norm() as f64)
+        .collect()
+}
+
+/// How strongly one sweep of one resonator shows the signs of bifurcation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bifurcation {
+    /// Peak IQ velocity over the larger of its two neighbours
+    pub jump: f64,
+    /// Peak IQ velocity over the median IQ velocity
+    pub prominence: f64,
+    /// Steepest |S21| slope on one side of the dip over the steepest on the other
+    pub asymmetry: f64,
+}
+
+impl Bifurcation {
+    pub fn new(row: ArrayView1<'_, Complex<f32>>) -> Bifurcation {
+        let v = iq_velocity(row);
+        if v.len() < 3 {
+            return Bifurcation {
+                jump: 1.,
+                prominence: 1.,
+                asymmetry: 1.,
+            };
+        }
+
+        let (peak, vmax) = v
+            .iter()
+            .copied()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .unwrap();
+        let neighbour = match peak {
+            0 => v[1],
+            p if p == v.len() - 1 => v[p - 1],
+            p => v[p - 1].max(v[p + 1]),
+        };
+        let mut sorted = v.clone();
+        sorted.sort_by(f64::total_cmp);
+        let median = sorted[sorted.len() / 2];
+
+        let mag: Vec<f64> = row.iter().map(|c| c.norm() as f64).collect();
+        let dip = mag
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map_or(0, |(i, _)| i);
+        let steepest = |m: &[f64]| m.windows(2).map(|w| (w[1] - w[0]).abs()).fold(0., f64::max);
+        let (left, right) = (steepest(&mag[..=dip]), steepest(&mag[dip..]));
+
+        Bifurcation {
+            jump: vmax / neighbour.max(f64::MIN_POSITIVE),
+            prominence: vmax / median.max(f64::MIN_POSITIVE),
+            asymmetry: left.max(right) / left.min(right).max(f64::MIN_POSITIVE),
+        }
+    }
+
+    /// A prominent discontinuity in the loop, or a prominent and lopsided dip
+    pub fn is_bifurcated(&self) -> bool {
+        self.prominence > PROMINENCE && (self.jump > JUMP_RATIO || self.asymmetry > ASYMMETRY)
+    }
+}
+
+/// Index into `values.iq` of the highest power not yet bifurcated for `resonator`
+///
+/// Walks up in power from the weakest drive and stops at the first bifurcated sweep, so
+/// a noisy high power sweep that happens to look clean doesn't win. `None` if even the
+/// weakest drive is bifurcated.
+pub fn onset(values: &PowerSweepValues, resonator: usize) -> Option<usize> {
+    // Sorted by output attenuation, so power goes down along iq
+    let mut best = None;
+    for (ai, (_, iq)) in values.iq.iter().enumerate().rev() {
+        if Bifurcation::new(iq.slice(s![resonator, ..])).is_bifurcated() {
+            break;
+        }
+        best = Some(ai);
+    }
+    best
+}
+
+/// A starting bias point for `resonator` as `(index into values.iq, step)`
+///
+/// Drives at the [`onset`] power, at the step where the loop moves fastest since that's
+/// where the readout is most responsive.
+pub fn suggest(values: &PowerSweepValues, resonator: usize) -> Option<(usize, usize)> {
+    let ai = onset(values, resonator)?;
+    let row = values.iq[ai].1.slice(s![resonator, ..]);
+    // Centered differences so the fastest point lands on a step rather than between two
+    let step = (1..row.len().saturating_sub(1))
+        .max_by(|a, b| {
+            let v = |k: usize| (row[k + 1] - row[k - 1]).norm();
+            v(*a).total_cmp(&v(*b))
+        })
+        .unwrap_or(0);
+    Some((ai, step))
+}
+
+#[cfg(test)]
+mod test {
+    use ndarray::Array2;
+
+    use super::*;
+
+    /// A resonator swept upwards through ±10 linewidths, nonlinear with parameter `a`
+    fn resonator(a: f64) -> Vec<Complex<f32>> {
+        let mut y = -10.;
+        (0..512)
+            .map(|i| {
+                let y0 = -10. + 20. * i as f64 / 511.;
+                // Continue along the current branch, which is what an upward sweep does
+                for _ in 0..100 {
+                    let f = y - y0 - a / (1. + 4. * y * y);
+                    let df = 1. + 8. * a * y / (1. + 4. * y * y).powi(2);
+                    y -= f / df;
+                }
+                let s21 = 1. - 0.8 / Complex::new(1., 2. * y);
+                Complex::new(s21.re as f32, s21.im as f32)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn velocity() {
+        let row = [
+            Complex::new(0., 0.),
+            Complex::new(3., 4.),
+            Complex::new(3., 5.),
+        ];
+        assert_eq!(iq_velocity(ArrayView1::from(&row)), vec![5., 1.]);
+    }
+
+    #[test]
+    fn linear_isnt_bifurcated() {
+        let row = resonator(0.);
+        assert!(!Bifurcation::new(ArrayView1::from(&row)).is_bifurcated());
+        // Just short of the critical a = 4√3/9
+        let row = resonator(0.7);
+        assert!(!Bifurcation::new(ArrayView1::from(&row)).is_bifurcated());
+    }
+
+    #[test]
+    fn bifurcated() {
+        for a in [0.8, 3.] {
+            let row = resonator(a);
+            let b = Bifurcation::new(ArrayView1::from(&row));
+            assert!(b.is_bifurcated(), "{a} {b:?}");
+        }
+    }
+
+    #[test]
+    fn onset_below_bifurcation() {
+        // Output attenuations 0 to 3 dB, bifurcated at 0 and 1 dB
+        let iq = |a: f64| Array2::from_shape_vec((1, 512), resonator(a)).unwrap();
+        let values = PowerSweepValues {
+            iq: vec![
+                ((0., 0.), iq(5.)),
+                ((1., 0.), iq(3.)),
+                ((2., 0.), iq(0.5)),
+                ((3., 0.), iq(0.2)),
+            ],
+            iqs: None,
+        };
+        assert_eq!(onset(&values, 0), Some(2));
+
+        assert_eq!(suggest(&values, 0).unwrap().0, 2);
+    }
+
+    #[test]
+    fn suggest_fastest() {
+        // A linear resonance is fastest at its center
+        let row = resonator(0.);
+        let center = row
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.norm().total_cmp(&b.norm()))
+            .unwrap()
+            .0;
+        let values = PowerSweepValues {
+            iq: vec![((0., 0.), Array2::from_shape_vec((1, 512), row).unwrap())],
+            iqs: None,
+        };
+        let (ai, step) = suggest(&values, 0).unwrap();
+        assert_eq!(ai, 0);
+        assert!(step.abs_diff(center) <= 1, "{step} {center}");
+    }
+}
@@ -1,41 +1,756 @@
+use std::collections::VecDeque;
 use std::io::{Read, Seek};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
 
 use colorous::VIRIDIS;
-use egui_plot::{Line, Plot, Points, VLine};
+use egui_plot::{Line, LineStyle, MarkerShape, Plot, Points, VLine};
 use ndarray::prelude::*;
-use ndarray_npy::NpzReader;
 
 use egui::{Color32, Id, Label};
-use num_complex::ComplexFloat;
+use num_complex::{Complex, ComplexFloat};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use crate::{PowerSweepConfig, PowerSweepValues};
+use crate::{
+    analysis,
+    correction::{self, Canonical, Correction},
+    fit::Fit,
+    BiasSetting, PowerSweep, ResonatorState,
+};
+
+/// Storage key for every [`Session`] by sweep identity
+const SESSIONS_KEY: &str = "sessions";
 
 pub struct ClickThrough {
-    gamma: f64,
-    psweep: PowerSweepConfig,
-    resonator: usize,
-    settings: HashMap<usize, BiasSetting>,
-    values: PowerSweepValues,
-    freq_range: (f64, f64),
+    /// Shared with the [`Fitter`]'s worker
+    sweep: Option<Arc<PowerSweep>>,
+    session: Session,
+    /// Sessions for sweeps other than the current one, by [`PowerSweep::identity`]
+    sessions: HashMap<String, Session>,
     freq_max: (f64, f64),
-    atten_range: (f64, f64),
     atten_max: (f64, f64),
     show_mag: bool,
+    mag_db: bool,
+    normalise: Normalise,
+    show_rms: bool,
+    show_onset: bool,
+    show_fit: bool,
+    loops: Loops,
+    /// The point hovered in the velocity or phase plot last frame, marked in the IQ plot
+    linked_hover: Option<BiasPoint>,
+    /// Show each loop fitted to a circle and moved onto the canonical notch
+    canonical: bool,
     show_settings: bool,
+    bulk_clear: BulkClear,
+    show_shortcuts: bool,
+    show_history: bool,
+    /// Selection edits of the current sweep
+    history: History,
+    /// Where the keyboard cursor is and which resonator it's on
+    cursor: Option<(usize, BiasPoint)>,
+    show_table: bool,
+    table: Table,
+    show_fits: bool,
+    show_heat_map: bool,
+    heat_map: HeatMap,
+    /// The heat map texture, along with the resonator, quantity, normalisation and gamma it
+    /// shows
+    heat_texture: Option<((usize, HeatMap, Normalise, f64), egui::TextureHandle)>,
+    show_validation: bool,
+    show_summary: bool,
+    /// List bad and collided resonators in the summary too
+    summary_excluded: bool,
+    /// How many resonators were unreviewed last frame, to bring up the summary as soon as
+    /// the last one is done
+    unreviewed: Option<usize>,
+    /// Shown to the user until dismissed
+    error: Option<String>,
+    /// Fits of the current sweep
+    fitter: Option<Fitter>,
+    /// Where to write the fits once every one is done, and how many there are
+    fits_export: Option<(std::path::PathBuf, usize)>,
+    /// Cable delays of the current sweep
+    delays: Delays,
+}
+
+/// What the heat map colours each (frequency, attenuation) pixel by
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum HeatMap {
+    /// Raw |S21| in dB, without the loops' delay and baseline correction, normalised by
+    /// [`ClickThrough::normalise`]
+    Magnitude,
+    /// IQ distance moved since the previous step, with the gamma gain applied
+    Velocity,
+}
+
+/// Which selections the Bias Settings window clears all at once
+struct BulkClear {
+    /// Those with output attenuation above `atten`, or else below it
+    above: bool,
+    atten: f64,
+}
+
+impl BulkClear {
+    fn matches(&self, setting: &BiasSetting) -> bool {
+        if self.above {
+            setting.output_atten > self.atten
+        } else {
+            setting.output_atten < self.atten
+        }
+    }
+}
+
+/// Sorting and filtering of the resonator table
+#[derive(Default)]
+struct Table {
+    sort: Column,
+    descending: bool,
+    /// Only rows whose index or tone frequency contains this
+    search: String,
+    filter: Filter,
+    /// Each resonator's tone frequency as shown and searched, formatted once per sweep
+    tones: Vec<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+enum Column {
+    #[default]
+    Index,
+    Freq,
+    Amp,
+    Atten,
+    Offset,
+    Status,
+    Residual,
+}
+
+impl Column {
+    const ALL: [Column; 7] = [
+        Column::Index,
+        Column::Freq,
+        Column::Amp,
+        Column::Atten,
+        Column::Offset,
+        Column::Status,
+        Column::Residual,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            Column::Index => "#",
+            Column::Freq => "Tone (MHz)",
+            Column::Amp => "Amp",
+            Column::Atten => "Atten",
+            Column::Offset => "Offset (MHz)",
+            Column::Status => "Status",
+            Column::Residual => "Fit",
+        }
+    }
+
+    fn width(self) -> f32 {
+        match self {
+            Column::Index => 36.,
+            Column::Freq => 84.,
+            Column::Status => 72.,
+            _ => 56.,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+enum Filter {
+    #[default]
+    All,
+    State(ResonatorState),
+    Suggested,
+}
+
+impl Filter {
+    fn all() -> impl Iterator<Item = Filter> {
+        std::iter::once(Filter::All)
+            .chain(ResonatorState::ALL.map(Filter::State))
+            .chain([Filter::Suggested])
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Filter::All => "All",
+            Filter::State(state) => state.label(),
+            Filter::Suggested => "Suggested",
+        }
+    }
+}
+
+/// What the magnitude plot and heat map divide each attenuation's |S21| by
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Normalise {
+    None,
+    /// Scaled by the gamma gain, like the loops
+    Gain,
+    /// Divided by its own level off resonance, so dip depths line up
+    OffResonance,
+}
+
+impl Normalise {
+    fn label(self) -> &'static str {
+        match self {
+            Normalise::None => "Unnormalised",
+            Normalise::Gain => "Gamma gain",
+            Normalise::OffResonance => "Off resonance",
+        }
+    }
+
+    /// What to multiply the `magnitudes` of one resonator's sweep at `(output, input)`
+    /// attenuation by
+    fn scale(self, (o, i): (f64, f64), gamma: f64, magnitudes: &[f64]) -> f64 {
+        match self {
+            Normalise::None => 1.,
+            Normalise::Gain => gain(i, o, gamma),
+            Normalise::OffResonance => 1. / correction::off_resonance(magnitudes),
+        }
+    }
+}
+
+/// How IQ loops are corrected before they're plotted
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Loops {
+    /// As measured, scaled by the gamma gain
+    Raw,
+    /// Divided by their baseline after taking out the cable delay of the whole sweep
+    DatasetDelay,
+    /// Divided by their baseline after taking out each resonator's own cable delay
+    ResonatorDelay,
+}
+
+impl Loops {
+    fn label(self) -> &'static str {
+        match self {
+            Loops::Raw => "Raw",
+            Loops::DatasetDelay => "Corrected, sweep delay",
+            Loops::ResonatorDelay => "Corrected, resonator delay",
+        }
+    }
+}
+
+/// Progress through a sweep, kept between runs so a long click-through can be resumed
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct Session {
+    resonator: usize,
+    gamma: f64,
+    /// Fixed attenuation of the lines down to the chip, for new settings' tone power
+    line_atten: f64,
+    settings: HashMap<usize, BiasSetting>,
+    /// Settings from an earlier sweep of the same resonators, shown for comparison
+    reference: HashMap<usize, BiasSetting>,
+    /// Resonators whose setting came from Suggest All and hasn't been reviewed
+    suggested: HashSet<usize>,
+    /// Review states set by hand, anything else is [`Session::state`]'s default
+    states: HashMap<usize, ResonatorState>,
+    /// Free text about each resonator, not part of the undo history
+    notes: HashMap<usize, String>,
+    freq_range: (f64, f64),
+    atten_range: (f64, f64),
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session {
+            resonator: 0,
+            gamma: 1.0,
+            line_atten: 0.,
+            settings: HashMap::new(),
+            reference: HashMap::new(),
+            suggested: HashSet::new(),
+            states: HashMap::new(),
+            notes: HashMap::new(),
+            freq_range: (0., 1.),
+            atten_range: (0., 1.),
+        }
+    }
+}
+
+impl Session {
+    /// Settings that aren't waiting on review as suggestions, the ones fit to export
+    fn reviewed_settings(&self) -> HashMap<usize, BiasSetting> {
+        self.settings
+            .iter()
+            .filter(|(r, _)| !self.suggested.contains(r))
+            .map(|(r, s)| (*r, s.clone()))
+            .collect()
+    }
+
+    /// The state set by hand, or else selected if there's a reviewed setting
+    fn state(&self, resonator: usize) -> ResonatorState {
+        match self.states.get(&resonator) {
+            Some(state) => *state,
+            None if self.settings.contains_key(&resonator)
+                && !self.suggested.contains(&resonator) =>
+            {
+                ResonatorState::Selected
+            }
+            None => ResonatorState::Unreviewed,
+        }
+    }
+
+    /// The first unreviewed resonator after `from`, wrapping around the `n` resonators,
+    /// or just the one after if everything's been reviewed, stopping at the last
+    fn next_unreviewed(&self, from: usize, n: usize) -> usize {
+        (1..n)
+            .map(|i| (from + i) % n)
+            .find(|r| self.state(*r) == ResonatorState::Unreviewed)
+            .unwrap_or((from + 1).min(n - 1))
+    }
+
+    /// Resonators out of the first `n` without a reviewed setting, in order
+    ///
+    /// One marked as needing review keeps its setting, so it isn't among them.
+    fn unselected(&self, n: usize) -> Vec<usize> {
+        (0..n)
+            .filter(|r| !self.settings.contains_key(r) || self.suggested.contains(r))
+            .collect()
+    }
+
+    /// Taking `bp` as the current resonator's setting and moving on to the next unreviewed
+    fn select(&self, sweep: &PowerSweep, bp: BiasPoint) -> Edit {
+        let r = self.resonator;
+        let bs = BiasSetting::new(
+            &sweep.config,
+            r,
+            sweep.values.iq[bp.atten].0,
+            bp.freq,
+            self.line_atten,
+        );
+        let kind = if self.settings.contains_key(&r) {
+            EditKind::Overwrite
+        } else {
+            EditKind::Insert
+        };
+        Edit::new(
+            self,
+            kind,
+            vec![Change::new(self, r, Some(bs), false)],
+            self.next_unreviewed(r, sweep.values.iq[0].1.nrows()),
+        )
+    }
+
+    /// Putting the current resonator in `state` and moving on to the next unreviewed
+    ///
+    /// States that exclude a setting drop it, others keep it as it is.
+    fn mark(&self, sweep: &PowerSweep, state: ResonatorState) -> Edit {
+        let r = self.resonator;
+        let change = if state.excludes_setting() {
+            Change::new(self, r, None, false)
+        } else {
+            Change::new(
+                self,
+                r,
+                self.settings.get(&r).cloned(),
+                self.suggested.contains(&r),
+            )
+        };
+        Edit::new(
+            self,
+            EditKind::Mark(state),
+            vec![change.with_state(Some(state))],
+            self.next_unreviewed(r, sweep.values.iq[0].1.nrows()),
+        )
+    }
+
+    /// Taking the reference setting of every resonator without a selection, rebuilt on
+    /// `sweep` so it's at one of its attenuations and steps
+    ///
+    /// References for resonators `sweep` doesn't have, that don't land on it, or that are
+    /// marked as not getting a setting are left out.
+    fn use_reference(&self, sweep: &PowerSweep) -> Edit {
+        let n = sweep.values.iq[0].1.nrows();
+        let mut changes: Vec<Change> = self
+            .reference
+            .iter()
+            .filter(|(r, _)| {
+                **r < n && !self.settings.contains_key(r) && !self.state(**r).excludes_setting()
+            })
+            .filter_map(|(r, s)| {
+                let bp = bias_point(sweep, *r, s)?;
+                let bs = BiasSetting::new(
+                    &sweep.config,
+                    *r,
+                    sweep.values.iq[bp.atten].0,
+                    bp.freq,
+                    self.line_atten,
+                );
+                Some(Change::new(self, *r, Some(bs), false))
+            })
+            .collect();
+        changes.sort_unstable_by_key(|c| c.resonator);
+        Edit::new(self, EditKind::Reference, changes, self.resonator)
+    }
+
+    /// Dropping the settings of `resonators`, putting them back to unreviewed, and staying
+    /// on the current resonator
+    fn clear(&self, resonators: impl IntoIterator<Item = usize>) -> Edit {
+        let changes = resonators
+            .into_iter()
+            .filter(|r| self.settings.contains_key(r))
+            .map(|r| Change::new(self, r, None, false))
+            .collect();
+        Edit::new(self, EditKind::Clear, changes, self.resonator)
+    }
+
+    /// Move to one side of `edit`, after it if `forward` and before it otherwise
+    fn apply(&mut self, edit: &Edit, forward: bool) {
+        let pick = |(before, after): &(bool, bool)| if forward { *after } else { *before };
+        for c in &edit.changes {
+            let setting = if forward { &c.setting.1 } else { &c.setting.0 };
+            match setting {
+                Some(s) => self.settings.insert(c.resonator, s.clone()),
+                None => self.settings.remove(&c.resonator),
+            };
+            if pick(&c.suggested) {
+                self.suggested.insert(c.resonator);
+            } else {
+                self.suggested.remove(&c.resonator);
+            }
+            match if forward { c.state.1 } else { c.state.0 } {
+                Some(state) => self.states.insert(c.resonator, state),
+                None => self.states.remove(&c.resonator),
+            };
+        }
+        self.resonator = if forward {
+            edit.resonator.1
+        } else {
+            edit.resonator.0
+        };
+    }
+}
+
+/// The kinds of selection edit that can be undone
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum EditKind {
+    Insert,
+    Overwrite,
+    Delete,
+    Mark(ResonatorState),
+    Accept,
+    Suggest,
+    Reference,
+    Clear,
+}
+
+/// One resonator's setting, suggested flag and hand set state before and after an
+/// [`Edit`]
+#[derive(Clone, Debug)]
+struct Change {
+    resonator: usize,
+    setting: (Option<BiasSetting>, Option<BiasSetting>),
+    suggested: (bool, bool),
+    state: (Option<ResonatorState>, Option<ResonatorState>),
+}
+
+impl Change {
+    /// From how `resonator` is in `session` to `setting`, clearing any hand set state
+    fn new(
+        session: &Session,
+        resonator: usize,
+        setting: Option<BiasSetting>,
+        suggested: bool,
+    ) -> Change {
+        Change {
+            resonator,
+            setting: (session.settings.get(&resonator).cloned(), setting),
+            suggested: (session.suggested.contains(&resonator), suggested),
+            state: (session.states.get(&resonator).copied(), None),
+        }
+    }
+
+    /// The same change, but leaving `resonator` in `state`
+    fn with_state(mut self, state: Option<ResonatorState>) -> Change {
+        self.state.1 = state;
+        self
+    }
+}
+
+/// An undoable change to the selections, and the move to another resonator that went
+/// with it
+#[derive(Clone, Debug)]
+struct Edit {
+    kind: EditKind,
+    changes: Vec<Change>,
+    /// The current resonator before and after
+    resonator: (usize, usize),
+}
+
+impl Edit {
+    fn new(session: &Session, kind: EditKind, changes: Vec<Change>, next: usize) -> Edit {
+        Edit {
+            kind,
+            changes,
+            resonator: (session.resonator, next),
+        }
+    }
+
+    /// One line for the history list
+    fn describe(&self) -> String {
+        let r = self.resonator.0;
+        let atten = |s: &Option<BiasSetting>| {
+            s.as_ref()
+                .map_or(String::new(), |s| format!(" at {} dB", s.output_atten))
+        };
+        let after = self
+            .changes
+            .first()
+            .map_or(String::new(), |c| atten(&c.setting.1));
+        match self.kind {
+            EditKind::Insert => format!("Selected {r}{after}"),
+            EditKind::Overwrite => format!("Reselected {r}{after}"),
+            EditKind::Delete => format!("Rejected {r}"),
+            EditKind::Mark(state) => format!("Marked {r} {}", state.label()),
+            EditKind::Accept => format!("Accepted {r}"),
+            EditKind::Suggest => format!("Suggested {} resonators", self.changes.len()),
+            EditKind::Reference => {
+                format!("Used the reference for {} resonators", self.changes.len())
+            }
+            EditKind::Clear => match self.changes.as_slice() {
+                [c] => format!("Cleared {}", c.resonator),
+                changes => format!("Cleared {} selections", changes.len()),
+            },
+        }
+    }
+}
+
+/// Edits that can be undone, and those undone that can be redone
+#[derive(Default)]
+struct History {
+    done: Vec<Edit>,
+    undone: Vec<Edit>,
+}
+
+impl History {
+    /// Make `edit` to `session`, dropping anything that could have been redone
+    fn push(&mut self, session: &mut Session, edit: Edit) {
+        if edit.changes.is_empty() && edit.resonator.0 == edit.resonator.1 {
+            return;
+        }
+        session.apply(&edit, true);
+        self.done.push(edit);
+        self.undone.clear();
+    }
+
+    fn undo(&mut self, session: &mut Session) {
+        if let Some(edit) = self.done.pop() {
+            session.apply(&edit, false);
+            self.undone.push(edit);
+        }
+    }
+
+    fn redo(&mut self, session: &mut Session) {
+        if let Some(edit) = self.undone.pop() {
+            session.apply(&edit, true);
+            self.done.push(edit);
+        }
+    }
+}
+
+/// Resonator fits done on a worker thread, so fitting a whole array doesn't hang the UI
+///
+/// Fits are asked for by resonator and index into `values.iq`, and land in `fits` as
+/// [`Fitter::poll`] picks them up. The web has no threads, so there each poll does one fit.
+struct Fitter {
+    /// Done so far, `None` for those that didn't converge
+    fits: HashMap<(usize, usize), Option<Fit>>,
+    /// Asked for and not back yet
+    pending: HashSet<(usize, usize)>,
+    queue: Arc<(Mutex<FitQueue>, Condvar)>,
+    results: Receiver<((usize, usize), Option<Fit>)>,
+    #[cfg(target_arch = "wasm32")]
+    sweep: Arc<PowerSweep>,
+    #[cfg(target_arch = "wasm32")]
+    sender: Sender<((usize, usize), Option<Fit>)>,
 }
 
-enum WasmReader<'a> {
+#[derive(Default)]
+struct FitQueue {
+    jobs: VecDeque<(usize, usize)>,
+    /// Set when the [`Fitter`] is dropped, so the worker stops
+    closed: bool,
+}
+
+impl Fitter {
+    fn new(sweep: Arc<PowerSweep>) -> Fitter {
+        let queue = Arc::new((Mutex::new(FitQueue::default()), Condvar::new()));
+        let (sender, results): (Sender<_>, _) = std::sync::mpsc::channel();
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let queue = queue.clone();
+            std::thread::spawn(move || loop {
+                let (r, ai) = {
+                    let (lock, ready) = &*queue;
+                    let mut queue = ready
+                        .wait_while(lock.lock().unwrap(), |q| q.jobs.is_empty() && !q.closed)
+                        .unwrap();
+                    match queue.jobs.pop_front() {
+                        Some(job) if !queue.closed => job,
+                        _ => return,
+                    }
+                };
+                if sender.send(((r, ai), fit(&sweep, r, ai))).is_err() {
+                    return;
+                }
+            });
+        }
+        Fitter {
+            fits: HashMap::new(),
+            pending: HashSet::new(),
+            queue,
+            results,
+            #[cfg(target_arch = "wasm32")]
+            sweep,
+            #[cfg(target_arch = "wasm32")]
+            sender,
+        }
+    }
+
+    /// The fit of `key` if it's done, asking for it ahead of anything queued otherwise
+    fn get(&mut self, key: (usize, usize)) -> Option<Option<Fit>> {
+        let fit = self.fits.get(&key).copied();
+        if fit.is_none() && self.pending.insert(key) {
+            self.queue.0.lock().unwrap().jobs.push_front(key);
+            self.queue.1.notify_one();
+        }
+        fit
+    }
+
+    /// Queue up every one of `keys` not already done or on its way, behind anything queued
+    fn request_all(&mut self, keys: impl IntoIterator<Item = (usize, usize)>) {
+        let mut queue = self.queue.0.lock().unwrap();
+        for key in keys {
+            if !self.fits.contains_key(&key) && self.pending.insert(key) {
+                queue.jobs.push_back(key);
+            }
+        }
+        self.queue.1.notify_one();
+    }
+
+    /// Drop everything queued, a fit already started still comes back
+    fn cancel(&mut self) {
+        self.queue.0.lock().unwrap().jobs.clear();
+        self.pending.clear();
+    }
+
+    /// Pick up any fits that are done
+    fn poll(&mut self) {
+        #[cfg(target_arch = "wasm32")]
+        if let Some((r, ai)) = self.queue.0.lock().unwrap().jobs.pop_front() {
+            let _ = self.sender.send(((r, ai), fit(&self.sweep, r, ai)));
+        }
+        for (key, fit) in self.results.try_iter() {
+            self.pending.remove(&key);
+            self.fits.insert(key, fit);
+        }
+    }
+}
+
+impl Drop for Fitter {
+    fn drop(&mut self) {
+        self.queue.0.lock().unwrap().closed = true;
+        self.queue.1.notify_all();
+    }
+}
+
+/// Cable delays of the current sweep, each worked out the first time it's needed
+///
+/// The sweep's delay fits every resonator, so it's found on a worker thread, natively.
+#[derive(Default)]
+struct Delays {
+    /// [`correction::dataset_delay`], once it's back
+    dataset: Option<f64>,
+    /// Where the dataset delay comes back from, once it's been asked for
+    worker: Option<Receiver<f64>>,
+    /// [`correction::delay`] of each resonator at the lowest power
+    resonators: HashMap<usize, f64>,
+}
+
+impl Delays {
+    /// The delay to take out of `resonator`'s loops for `loops`, `None` if they're raw or
+    /// the sweep's delay isn't back yet
+    fn get(&mut self, sweep: &Arc<PowerSweep>, loops: Loops, resonator: usize) -> Option<f64> {
+        match loops {
+            Loops::Raw => None,
+            Loops::DatasetDelay => {
+                if self.dataset.is_none() {
+                    let worker = self.worker.get_or_insert_with(|| {
+                        let (sender, worker) = std::sync::mpsc::channel();
+                        let sweep = sweep.clone();
+                        let find = move || {
+                            let _ = sender.send(correction::dataset_delay(&sweep.values, |r| {
+                                sweep.config.sweep_config.tone_freqs(r)
+                            }));
+                        };
+                        #[cfg(not(target_arch = "wasm32"))]
+                        std::thread::spawn(find);
+                        #[cfg(target_arch = "wasm32")]
+                        find();
+                        worker
+                    });
+                    self.dataset = worker.try_recv().ok();
+                }
+                self.dataset
+            }
+            // From the lowest power, where the loop is most nearly a circle
+            Loops::ResonatorDelay => Some(*self.resonators.entry(resonator).or_insert_with(|| {
+                let (_, iq) = &sweep.values.iq[sweep.values.iq.len() - 1];
+                correction::delay(
+                    &sweep.config.sweep_config.tone_freqs(resonator),
+                    iq.slice(s![resonator, ..]),
+                )
+            })),
+        }
+    }
+
+    /// Whether the sweep's delay has been asked for and isn't back yet
+    fn finding(&self) -> bool {
+        self.worker.is_some() && self.dataset.is_none()
+    }
+}
+
+/// Key and what it does, handled in [`ClickThrough::keys`]
+const SHORTCUTS: [(&str, &str); 13] = [
+    (
+        "← →",
+        "Move the cursor along the sweep, by 10 steps with Shift",
+    ),
+    ("↑ ↓", "Move the cursor up or down in power"),
+    (
+        "Enter / Space",
+        "Select the cursor and go to the next resonator",
+    ),
+    ("S", "Skip the resonator without selecting"),
+    ("B", "Mark the resonator bad"),
+    ("C", "Mark the resonator as a collision"),
+    (
+        "R",
+        "Flag the resonator as needing review, keeping any setting",
+    ),
+    ("N / Page Down", "Next resonator"),
+    ("P / Page Up", "Previous resonator"),
+    ("Home", "Put the cursor back on the setting or suggestion"),
+    ("Delete", "Clear the resonator's setting"),
+    ("Ctrl+Z", "Undo"),
+    ("Ctrl+Shift+Z", "Redo"),
+];
+
+/// An opened file, from disk natively or dropped into the browser on the web
+enum Source {
     File(std::fs::File),
-    Bytes { buffer: &'a [u8], cursor: usize },
+    Bytes { buffer: Arc<[u8]>, cursor: usize },
 }
 
-impl Read for WasmReader<'_> {
+impl Read for Source {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         match self {
-            WasmReader::File(f) => f.read(buf),
-            WasmReader::Bytes { buffer, cursor } => {
+            Source::File(f) => f.read(buf),
+            Source::Bytes { buffer, cursor } => {
                 let mut read = 0usize;
                 while *cursor < buffer.len() && read < buf.len() {
                     buf[read] = buffer[*cursor];
@@ -48,11 +763,11 @@ impl Read for WasmReader<'_> {
     }
 }
 
-impl Seek for WasmReader<'_> {
+impl Seek for Source {
     fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
         match self {
-            WasmReader::File(f) => f.seek(pos),
-            WasmReader::Bytes { buffer, cursor } => {
+            Source::File(f) => f.seek(pos),
+            Source::Bytes { buffer, cursor } => {
                 match pos {
                     std::io::SeekFrom::Start(i) => {
                         *cursor = i as usize;
@@ -86,40 +801,538 @@ impl Seek for WasmReader<'_> {
 
 impl ClickThrough {
     /// Called once before the first frame.
-    pub fn new(_cc: &eframe::CreationContext<'_>) -> Self {
+    pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
         // This is also where you can customize the look and feel of egui using
         // `cc.egui_ctx.set_visuals` and `cc.egui_ctx.set_fonts`.
 
-        // Load previous app state (if any).
-        // Note that you must enable the `persistence` feature for this to work.
-        // if let Some(storage) = cc.storage {
-        //     return eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default();
-        // }
-        let (mut jf, mut pf) = {
-            if cfg!(target_arch = "wasm32") {
-                (
-                    WasmReader::Bytes {
-                        buffer: include_bytes!("../psweepconfig.json"),
-                        cursor: 0,
-                    },
-                    WasmReader::Bytes {
-                        buffer: include_bytes!("../psweep.npz"),
-                        cursor: 0,
-                    },
-                )
+        // Sessions are only restored once their sweep is opened again
+        let sessions = cc
+            .storage
+            .and_then(|storage| eframe::get_value(storage, SESSIONS_KEY))
+            .unwrap_or_default();
+
+        ClickThrough {
+            sweep: None,
+            session: Session::default(),
+            sessions,
+            freq_max: (0., 1.),
+            atten_max: (0., 1.),
+            show_mag: true,
+            mag_db: false,
+            normalise: Normalise::Gain,
+            show_rms: false,
+            show_onset: true,
+            show_fit: false,
+            loops: Loops::Raw,
+            linked_hover: None,
+            canonical: false,
+            show_settings: false,
+            bulk_clear: BulkClear {
+                above: true,
+                atten: 0.,
+            },
+            show_shortcuts: false,
+            show_history: false,
+            history: History::default(),
+            cursor: None,
+            show_table: true,
+            table: Table::default(),
+            show_fits: false,
+            show_heat_map: false,
+            heat_map: HeatMap::Magnitude,
+            heat_texture: None,
+            show_validation: false,
+            show_summary: false,
+            summary_excluded: false,
+            unreviewed: None,
+            error: None,
+            fitter: None,
+            fits_export: None,
+            delays: Delays::default(),
+        }
+    }
+
+    /// Open a power sweep from a config `.json` and a sweep `.npz` on disk, given in any order
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn open_paths(&mut self, paths: Vec<std::path::PathBuf>) {
+        let mut files = Vec::with_capacity(paths.len());
+        for p in paths {
+            match std::fs::File::open(&p) {
+                Ok(f) => files.push((p.to_string_lossy().into_owned(), Source::File(f))),
+                Err(e) => {
+                    self.error = Some(format!("Couldn't open {}: {e}", p.display()));
+                    return;
+                }
+            }
+        }
+        self.open_files(files);
+    }
+
+    /// Pick out the config and sweep from a set of named files and load them
+    fn open_files(&mut self, files: Vec<(String, Source)>) {
+        let mut config = None;
+        let mut npz = None;
+        for (name, reader) in files {
+            if name.ends_with(".json") {
+                config = Some(reader);
+            } else if name.ends_with(".npz") {
+                npz = Some(reader);
             } else {
-                (
-                    WasmReader::File(std::fs::File::open("./psweepconfig.json").unwrap()),
-                    WasmReader::File(std::fs::File::open("./psweep.npz").unwrap()),
-                )
+                log::warn!("Ignoring {name}, expected a .json config or .npz sweep");
+            }
+        }
+
+        let (Some(config), Some(npz)) = (config, npz) else {
+            self.error = Some("A power sweep needs both a .json config and a .npz sweep".into());
+            return;
+        };
+        match PowerSweep::from_readers(config, npz) {
+            Ok(sweep) => self.load(sweep),
+            Err(e) => self.error = Some(format!("Couldn't load power sweep: {e}")),
+        }
+    }
+
+    /// Ask where to write the selected bias settings and write them there in the format
+    /// matching the chosen extension
+    #[cfg(not(target_arch = "wasm32"))]
+    fn export(&mut self) {
+        use crate::export;
+
+        let Some(sweep) = &self.sweep else {
+            return;
+        };
+        let Some(path) = rfd::FileDialog::new()
+            .set_title("Export bias settings")
+            .add_filter("Waveform JSON", &["json"])
+            .add_filter("CSV", &["csv"])
+            .add_filter("NumPy", &["npz"])
+            .set_file_name("bias_settings.json")
+            .save_file()
+        else {
+            return;
+        };
+
+        // Suggestions nobody has accepted don't go anywhere near the readout
+        let (config, settings) = (&sweep.config, &self.session.reviewed_settings());
+        let result = std::fs::File::create(&path)
+            .map(std::io::BufWriter::new)
+            .map_err(|e| e.to_string())
+            .and_then(|f| match path.extension().and_then(|e| e.to_str()) {
+                Some("csv") => export::write_csv(f, config, settings).map_err(|e| e.to_string()),
+                Some("npz") => export::write_npz(f, config, settings).map_err(|e| e.to_string()),
+                _ => export::write_json(f, config, settings).map_err(|e| e.to_string()),
+            });
+        if let Err(e) = result {
+            self.error = Some(format!("Couldn't export to {}: {e}", path.display()));
+        }
+    }
+
+    /// Ask where to write fits of every resonator at every power, and start fitting any
+    /// not done yet, the file is written by [`ClickThrough::finish_fits_export`]
+    #[cfg(not(target_arch = "wasm32"))]
+    fn export_fits(&mut self) {
+        let (Some(sweep), Some(fitter)) = (&self.sweep, &mut self.fitter) else {
+            return;
+        };
+        let Some(path) = rfd::FileDialog::new()
+            .set_title("Export resonator fits")
+            .add_filter("CSV", &["csv"])
+            .set_file_name("fits.csv")
+            .save_file()
+        else {
+            return;
+        };
+
+        let keys: Vec<_> = (0..sweep.values.iq[0].1.nrows())
+            .flat_map(|r| (0..sweep.values.iq.len()).map(move |ai| (r, ai)))
+            .collect();
+        self.fits_export = Some((path, keys.len()));
+        fitter.request_all(keys);
+    }
+
+    /// Write the fits asked for by [`ClickThrough::export_fits`] once they're all done
+    #[cfg(not(target_arch = "wasm32"))]
+    fn finish_fits_export(&mut self) {
+        let (Some(sweep), Some(fitter)) = (&self.sweep, &self.fitter) else {
+            return;
+        };
+        if self.fits_export.is_none() || !fitter.pending.is_empty() {
+            return;
+        }
+        let Some((path, _)) = self.fits_export.take() else {
+            return;
+        };
+
+        let mut fits = Vec::new();
+        for r in 0..sweep.values.iq[0].1.nrows() {
+            for (ai, (atten, _)) in sweep.values.iq.iter().enumerate() {
+                if let Some(Some(fit)) = fitter.fits.get(&(r, ai)) {
+                    fits.push((r, *atten, *fit));
+                }
             }
+        }
+        let result = std::fs::File::create(&path)
+            .and_then(|f| crate::export::write_fits_csv(std::io::BufWriter::new(f), &fits));
+        if let Err(e) = result {
+            self.error = Some(format!("Couldn't export to {}: {e}", path.display()));
+        }
+    }
+
+    /// Ask where to write every resonator's review state and note
+    #[cfg(not(target_arch = "wasm32"))]
+    fn export_review(&mut self) {
+        let Some(sweep) = &self.sweep else {
+            return;
+        };
+        let Some(path) = rfd::FileDialog::new()
+            .set_title("Export review states")
+            .add_filter("CSV", &["csv"])
+            .set_file_name("review.csv")
+            .save_file()
+        else {
+            return;
+        };
+
+        let session = &self.session;
+        let review: Vec<_> = (0..sweep.values.iq[0].1.nrows())
+            .map(|r| {
+                let note = session.notes.get(&r).map_or("", String::as_str);
+                (r, session.state(r), note)
+            })
+            .collect();
+        let result = std::fs::File::create(&path)
+            .and_then(|f| crate::export::write_review_csv(std::io::BufWriter::new(f), &review));
+        if let Err(e) = result {
+            self.error = Some(format!("Couldn't export to {}: {e}", path.display()));
+        }
+    }
+
+    /// Ask for bias settings exported from an earlier sweep and show them as a reference
+    #[cfg(not(target_arch = "wasm32"))]
+    fn import_reference(&mut self) {
+        let Some(sweep) = &self.sweep else {
+            return;
+        };
+        let Some(path) = rfd::FileDialog::new()
+            .set_title("Import reference bias settings")
+            .add_filter("Waveform JSON", &["json"])
+            .pick_file()
+        else {
+            return;
         };
 
-        let mut json = String::new();
-        jf.read_to_string(&mut json).unwrap();
-        let mut pv = NpzReader::new(&mut pf).unwrap();
-        let psweep: PowerSweepConfig = serde_json::from_str(&json).unwrap();
+        let result = std::fs::File::open(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|f| {
+                crate::export::read_json(std::io::BufReader::new(f), &sweep.config)
+                    .map_err(|e| e.to_string())
+            });
+        match result {
+            Ok(reference) => self.session.reference = reference,
+            Err(e) => self.error = Some(format!("Couldn't import {}: {e}", path.display())),
+        }
+    }
 
+    /// Where the cursor starts on a resonator, its setting if it has one, then the
+    /// suggested point, then the middle of the lowest power sweep
+    fn home(&self, sweep: &PowerSweep) -> BiasPoint {
+        let r = self.session.resonator;
+        self.session
+            .settings
+            .get(&r)
+            .and_then(|s| bias_point(sweep, r, s))
+            .or_else(|| {
+                analysis::suggest(&sweep.values, r).map(|(atten, freq)| BiasPoint { atten, freq })
+            })
+            .unwrap_or(BiasPoint {
+                atten: sweep.values.iq.len() - 1,
+                freq: sweep.config.sweep_config.steps.len() / 2,
+            })
+    }
+
+    /// Act on the keys in [`SHORTCUTS`], unless they're being typed into something
+    fn keys(&mut self, ctx: &egui::Context) {
+        use egui::{Key, Modifiers};
+
+        let Some(sweep) = &self.sweep else {
+            return;
+        };
+        if ctx.wants_keyboard_input() {
+            return;
+        }
+        let resonators = sweep.values.iq[0].1.nrows();
+        let (steps, attens) = (sweep.config.sweep_config.steps.len(), sweep.values.iq.len());
+        let resonator = self.session.resonator;
+        let mut cursor = match self.cursor {
+            Some((r, bp)) if r == resonator => bp,
+            _ => self.home(sweep),
+        };
+
+        let (mut select, mut mark, mut step) = (false, None, 0isize);
+        let mut clear = false;
+        let (mut undo, mut redo) = (false, false);
+        ctx.input_mut(|i| {
+            // Shift is ignored when matching the plain keys, so these go first
+            redo = i.consume_key(Modifiers::COMMAND | Modifiers::SHIFT, Key::Z);
+            undo = i.consume_key(Modifiers::COMMAND, Key::Z);
+            if i.consume_key(Modifiers::SHIFT, Key::ArrowRight) {
+                cursor.freq = (cursor.freq + 10).min(steps - 1);
+            }
+            if i.consume_key(Modifiers::SHIFT, Key::ArrowLeft) {
+                cursor.freq = cursor.freq.saturating_sub(10);
+            }
+            let mut pressed = |key| i.consume_key(Modifiers::NONE, key);
+            if pressed(Key::ArrowRight) {
+                cursor.freq = (cursor.freq + 1).min(steps - 1);
+            }
+            if pressed(Key::ArrowLeft) {
+                cursor.freq = cursor.freq.saturating_sub(1);
+            }
+            // Lower attenuation is more power, and comes first
+            if pressed(Key::ArrowUp) {
+                cursor.atten = cursor.atten.saturating_sub(1);
+            }
+            if pressed(Key::ArrowDown) {
+                cursor.atten = (cursor.atten + 1).min(attens - 1);
+            }
+            if pressed(Key::Home) {
+                cursor = self.home(sweep);
+            }
+            select = pressed(Key::Enter) || pressed(Key::Space);
+            clear = pressed(Key::Delete);
+            for (key, state) in [
+                (Key::S, ResonatorState::Skipped),
+                (Key::B, ResonatorState::Bad),
+                (Key::C, ResonatorState::Collision),
+                (Key::R, ResonatorState::NeedsReview),
+            ] {
+                if pressed(key) {
+                    mark = Some(state);
+                }
+            }
+            if pressed(Key::N) || pressed(Key::PageDown) {
+                step = 1;
+            }
+            if pressed(Key::P) || pressed(Key::PageUp) {
+                step = -1;
+            }
+        });
+        self.cursor = Some((resonator, cursor));
+
+        if undo {
+            self.history.undo(&mut self.session);
+        } else if redo {
+            self.history.redo(&mut self.session);
+        } else if select {
+            let edit = self.session.select(sweep, cursor);
+            self.history.push(&mut self.session, edit);
+        } else if let Some(state) = mark {
+            let edit = self.session.mark(sweep, state);
+            self.history.push(&mut self.session, edit);
+        } else if clear {
+            let edit = self.session.clear([resonator]);
+            self.history.push(&mut self.session, edit);
+        } else {
+            self.session.resonator = resonator.saturating_add_signed(step).min(resonators - 1);
+        }
+    }
+
+    /// Fill in a suggested setting for every resonator that doesn't have one yet, and
+    /// hasn't been marked as not getting one
+    fn suggest_all(&mut self) {
+        let Some(sweep) = &self.sweep else {
+            return;
+        };
+        let mut changes = Vec::new();
+        for r in 0..sweep.values.iq[0].1.nrows() {
+            if self.session.settings.contains_key(&r) || self.session.state(r).excludes_setting() {
+                continue;
+            }
+            if let Some((ai, step)) = analysis::suggest(&sweep.values, r) {
+                let bs = BiasSetting::new(
+                    &sweep.config,
+                    r,
+                    sweep.values.iq[ai].0,
+                    step,
+                    self.session.line_atten,
+                );
+                changes.push(Change::new(&self.session, r, Some(bs), true));
+            }
+        }
+        let edit = Edit::new(
+            &self.session,
+            EditKind::Suggest,
+            changes,
+            self.session.resonator,
+        );
+        self.history.push(&mut self.session, edit);
+    }
+
+    /// Side panel listing every resonator, click a row to jump to it
+    fn table(&mut self, ui: &mut egui::Ui) {
+        let Some(sweep) = &self.sweep else {
+            return;
+        };
+        let config = &sweep.config.sweep_config;
+        let session = &self.session;
+        let table = &mut self.table;
+
+        ui.horizontal(|ui| {
+            ui.label("Search");
+            ui.text_edit_singleline(&mut table.search)
+                .on_hover_text("Index, tone frequency or note");
+        });
+        ui.horizontal(|ui| {
+            ui.label("Show");
+            egui::ComboBox::from_id_salt("table filter")
+                .selected_text(table.filter.label())
+                .show_ui(ui, |ui| {
+                    for filter in Filter::all() {
+                        ui.selectable_value(&mut table.filter, filter, filter.label());
+                    }
+                });
+        });
+        ui.separator();
+
+        let tone = |r: usize| (config.waveform.freqs[r] + config.lo_center * 1e6) / 1e6;
+        let n = sweep.values.iq[0].1.nrows();
+        if table.tones.len() != n {
+            table.tones = (0..n).map(|r| format!("{:.3}", tone(r))).collect();
+        }
+        let status = |r: usize| match session.state(r) {
+            ResonatorState::Unreviewed if session.suggested.contains(&r) => "Suggested",
+            ResonatorState::Unreviewed => "",
+            state => state.label(),
+        };
+        // Only fits already done, fitting every row here would stall the UI or bury the
+        // current resonator's fits behind the whole table
+        let residual = |r: usize| {
+            let setting = session.settings.get(&r)?;
+            let ai = bias_point(sweep, r, setting)?.atten;
+            self.fitter
+                .as_ref()?
+                .fits
+                .get(&(r, ai))
+                .copied()
+                .flatten()
+                .map(|f| f.residual)
+        };
+
+        let search = table.search.trim();
+        let lowercase = search.to_lowercase();
+        let rows = (0..n)
+            .filter(|r| match table.filter {
+                Filter::All => true,
+                Filter::State(state) => session.state(*r) == state,
+                Filter::Suggested => session.suggested.contains(r),
+            })
+            .filter(|r| {
+                search.is_empty()
+                    || r.to_string().contains(search)
+                    || table.tones[*r].contains(search)
+                    || session
+                        .notes
+                        .get(r)
+                        .is_some_and(|n| n.to_lowercase().contains(&lowercase))
+            });
+        let setting = |r: &usize| session.settings.get(r);
+        let key = |r: &usize| -> f64 {
+            match table.sort {
+                Column::Index => *r as f64,
+                Column::Freq => tone(*r),
+                Column::Amp => config.waveform.amps[*r],
+                Column::Atten => setting(r).map_or(f64::NAN, |s| s.output_atten),
+                Column::Offset => setting(r).map_or(f64::NAN, |s| config.steps[s.step]),
+                // Suggested sorts just after unreviewed, then the states in order
+                Column::Status => match session.state(*r) {
+                    ResonatorState::Unreviewed if session.suggested.contains(r) => 0.5,
+                    state => ResonatorState::ALL
+                        .iter()
+                        .position(|s| *s == state)
+                        .unwrap() as f64,
+                },
+                Column::Residual => residual(*r).unwrap_or(f64::NAN),
+            }
+        };
+        // Keys worked out once a row rather than once a comparison, then a stable sort, so
+        // ties stay in index order, and anything missing goes last either way
+        let mut keyed: Vec<(f64, usize)> = rows.map(|r| (key(&r), r)).collect();
+        keyed.sort_by(|(a, _), (b, _)| match (a.is_nan(), b.is_nan()) {
+            (false, false) if table.descending => b.total_cmp(a),
+            (false, false) => a.total_cmp(b),
+            (a, b) => a.cmp(&b),
+        });
+        let rows: Vec<usize> = keyed.into_iter().map(|(_, r)| r).collect();
+
+        let height = ui.text_style_height(&egui::TextStyle::Body) + 4.;
+        ui.horizontal(|ui| {
+            for column in Column::ALL {
+                let arrow = match (table.sort == column, table.descending) {
+                    (false, _) => "",
+                    (true, false) => " ⏶",
+                    (true, true) => " ⏷",
+                };
+                let header = egui::SelectableLabel::new(
+                    table.sort == column,
+                    egui::RichText::new(format!("{}{arrow}", column.label())).strong(),
+                );
+                if ui.add_sized([column.width(), height], header).clicked() {
+                    table.descending = table.sort == column && !table.descending;
+                    table.sort = column;
+                }
+            }
+        });
+
+        let mut jump = None;
+        egui::ScrollArea::vertical().auto_shrink(false).show_rows(
+            ui,
+            height,
+            rows.len(),
+            |ui, range| {
+                for r in rows[range].iter().copied() {
+                    let setting = setting(&r);
+                    let flag = if session.state(r) == ResonatorState::NeedsReview {
+                        " ⚑"
+                    } else {
+                        ""
+                    };
+                    let cells = [
+                        format!("{r}{flag}"),
+                        table.tones[r].clone(),
+                        format!("{:.3}", config.waveform.amps[r]),
+                        setting.map_or(String::new(), |s| format!("{}", s.output_atten)),
+                        setting.map_or(String::new(), |s| format!("{:.4}", config.steps[s.step])),
+                        status(r).to_owned(),
+                        match (setting, residual(r)) {
+                            (None, _) => String::new(),
+                            (Some(_), None) => "–".to_owned(),
+                            (Some(_), Some(residual)) => format!("{residual:.1e}"),
+                        },
+                    ];
+                    ui.horizontal(|ui| {
+                        for (column, text) in Column::ALL.iter().zip(cells) {
+                            let cell = egui::SelectableLabel::new(session.resonator == r, text);
+                            if ui.add_sized([column.width(), height], cell).clicked() {
+                                jump = Some(r);
+                            }
+                        }
+                    });
+                }
+            },
+        );
+        if let Some(r) = jump {
+            self.session.resonator = r;
+        }
+    }
+
+    /// Replace the current sweep, picking up where we left off if it's been seen before
+    fn load(&mut self, sweep: PowerSweep) {
+        if let Some(old) = self.sweep.take() {
+            self.sessions
+                .insert(old.identity(), std::mem::take(&mut self.session));
+        }
+
+        let psweep = &sweep.config;
         let maxo: f64 = psweep.attens.iter().fold(f64::MIN, |a, b| a.max(b.0));
         let mino: f64 = psweep.attens.iter().fold(f64::MAX, |a, b| a.min(b.0));
         let maxf: f64 = psweep
@@ -133,42 +1346,215 @@ impl ClickThrough {
             .iter()
             .fold(f64::MAX, |a, b| a.min(*b));
 
-        ClickThrough {
-            gamma: 1.0,
-            resonator: 0,
-            psweep,
-            values: PowerSweepValues::from_reader(&mut pv),
-            settings: HashMap::new(),
-            freq_range: (minf, maxf),
-            freq_max: (minf, maxf),
-            atten_range: (mino, maxo),
-            atten_max: (mino, maxo),
-            show_mag: true,
-            show_settings: false,
-        }
+        self.freq_max = (minf, maxf);
+        self.atten_max = (mino, maxo);
+        self.session = match self.sessions.remove(&sweep.identity()) {
+            Some(session) => Session {
+                freq_range: (
+                    session.freq_range.0.max(minf),
+                    session.freq_range.1.min(maxf),
+                ),
+                atten_range: (
+                    session.atten_range.0.max(mino),
+                    session.atten_range.1.min(maxo),
+                ),
+                resonator: session.resonator.min(sweep.values.iq[0].1.nrows() - 1),
+                ..session
+            },
+            None => Session {
+                freq_range: (minf, maxf),
+                atten_range: (mino, maxo),
+                ..Default::default()
+            },
+        };
+        self.show_validation = !sweep.report.is_clean();
+        let sweep = Arc::new(sweep);
+        self.fitter = Some(Fitter::new(sweep.clone()));
+        self.fits_export = None;
+        self.heat_texture = None;
+        self.history = History::default();
+        self.delays = Delays::default();
+        self.table.tones.clear();
+        self.unreviewed = None;
+        self.sweep = Some(sweep);
     }
 }
 
 #[derive(Hash, PartialEq, Eq, Clone, Copy, Debug)]
 struct BiasPoint {
-    output_atten: usize,
+    /// Index into [`PowerSweepValues::iq`](crate::PowerSweepValues::iq), which is sorted by attenuation
+    atten: usize,
     freq: usize,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct BiasSetting {
-    output_atten: f64,
-    amp: f64,
-    freq: f64,
+/// The closest point in the sweep to `setting`, if it falls within the swept range
+fn bias_point(sweep: &PowerSweep, resonator: usize, setting: &BiasSetting) -> Option<BiasPoint> {
+    let config = &sweep.config.sweep_config;
+    let atten = sweep
+        .values
+        .position((setting.output_atten, setting.input_atten))
+        .or_else(|| {
+            sweep
+                .values
+                .iq
+                .iter()
+                .position(|((o, _), _)| *o == setting.output_atten)
+        })?;
+    let offset = (setting.freq - config.waveform.freqs[resonator] - config.lo_center * 1e6) / 1e6;
+    let (freq, step) = config
+        .steps
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| (*a - offset).abs().total_cmp(&(*b - offset).abs()))?;
+    let spacing = (config.steps[config.steps.len() - 1] - config.steps[0]).abs()
+        / (config.steps.len() as f64 - 1.).max(1.);
+    ((step - offset).abs() <= spacing).then_some(BiasPoint { atten, freq })
 }
 
-/// atten index, output atten, loop, amp plot
-type IQs = (usize, f64, Vec<[f64; 2]>, Vec<[f64; 2]>);
+/// Fit `resonator` at the power of `values.iq[ai]`
+fn fit(sweep: &PowerSweep, resonator: usize, ai: usize) -> Option<Fit> {
+    crate::fit::fit(
+        &sweep.config.sweep_config.tone_freqs(resonator),
+        sweep.values.iq[ai].1.slice(s![resonator, ..]),
+    )
+}
+
+/// `resonator`'s sweep as an image with a pixel per step across and per attenuation up,
+/// lowest attenuation at the bottom, from the raw sweep whatever the loops are corrected by
+fn heat_map(
+    sweep: &PowerSweep,
+    resonator: usize,
+    quantity: HeatMap,
+    normalise: Normalise,
+    gamma: f64,
+) -> egui::ColorImage {
+    let iq = &sweep.values.iq;
+    let steps = sweep.config.sweep_config.steps.len();
+    let rows: Vec<Vec<f64>> = iq
+        .iter()
+        .rev()
+        .map(|((o, i), iq)| {
+            let row = iq.slice(s![resonator, ..]);
+            match quantity {
+                HeatMap::Magnitude => {
+                    let magnitudes: Vec<f64> = row.iter().map(|c| c.abs() as f64).collect();
+                    let scale = normalise.scale((*o, *i), gamma, &magnitudes);
+                    magnitudes
+                        .iter()
+                        .map(|m| 20. * (m * scale).log10())
+                        .collect()
+                }
+                HeatMap::Velocity => {
+                    let gain = gain(*i, *o, gamma);
+                    let v = analysis::iq_velocity(row);
+                    // The first step has nothing before it, so repeat the second
+                    v.first()
+                        .into_iter()
+                        .chain(v.iter())
+                        .map(|v| v * gain)
+                        .collect()
+                }
+            }
+        })
+        .collect();
+    let finite = rows.iter().flatten().filter(|v| v.is_finite());
+    let (min, max) = finite.fold((f64::MAX, f64::MIN), |(a, b), v| (a.min(*v), b.max(*v)));
+    let pixels = rows
+        .iter()
+        .flatten()
+        .map(|v| {
+            let t = ((v - min) / (max - min)).clamp(0., 1.);
+            let c = VIRIDIS.eval_continuous(if t.is_finite() { t } else { 0. });
+            Color32::from_rgb(c.r, c.g, c.b)
+        })
+        .collect();
+    egui::ColorImage {
+        size: [steps, iq.len()],
+        pixels,
+    }
+}
+
+/// The stretch of the heat map's attenuation axis each of `attens`, sorted, gets
+///
+/// Each distinct attenuation reaches halfway to its neighbours, so a missing one doesn't
+/// shift the rest, and rows sharing an output attenuation split its stretch in order.
+fn bands(attens: &[f64]) -> Vec<[f64; 2]> {
+    let mut distinct = attens.to_vec();
+    distinct.dedup();
+    let mut bands = Vec::with_capacity(attens.len());
+    for (k, a) in distinct.iter().enumerate() {
+        // The outermost reach as far out as the gap next to them, or half a dB alone
+        let gap = match (k.checked_sub(1).map(|k| distinct[k]), distinct.get(k + 1)) {
+            (Some(below), _) if k + 1 == distinct.len() => a - below,
+            (_, Some(above)) => above - a,
+            _ => 1.,
+        };
+        let low = k
+            .checked_sub(1)
+            .map_or(a - gap / 2., |k| (distinct[k] + a) / 2.);
+        let high = distinct
+            .get(k + 1)
+            .map_or(a + gap / 2., |above| (a + above) / 2.);
+        let count = attens.iter().filter(|o| *o == a).count();
+        let width = (high - low) / count as f64;
+        bands.extend((0..count).map(|j| [low + j as f64 * width, low + (j + 1) as f64 * width]));
+    }
+    bands
+}
+
+/// Amplitude gain making up for the `(output, input)` attenuation, with `gamma` weighting
+/// the output attenuation
+fn gain(i: f64, o: f64, gamma: f64) -> f64 {
+    (10f64.powf((i + o * gamma) / 10.)).sqrt()
+}
+
+/// Colour of the sweep at `values.iq[ai]` by where its output attenuation is in
+/// `atten_range`, brightest at the highest power
+fn colour(sweep: &PowerSweep, (low, high): (f64, f64), ai: usize) -> Color32 {
+    let o = sweep.values.iq[ai].0 .0;
+    let c = VIRIDIS.eval_continuous(1. - (o - low) / (high - low));
+    Color32::from_rgb(c.r, c.g, c.b)
+}
+
+/// A large hollow marker picking out one point of a plot
+fn marker(point: [f64; 2], shape: MarkerShape, color: Color32, name: &str) -> Points {
+    Points::new(vec![point])
+        .shape(shape)
+        .filled(false)
+        .radius(8.)
+        .color(color)
+        .allow_hover(false)
+        .name(name)
+}
+
+/// atten index, output atten, loop, amp plot, and the I and Q RMS about each loop point as
+/// they lie in the loop plot (empty if not recorded)
+type IQs = (usize, f64, Vec<[f64; 2]>, Vec<[f64; 2]>, Vec<[[f64; 2]; 2]>);
+
+/// Outline of the ellipse about `c` with conjugate semi-axes `u` and `w`
+fn ellipse(c: [f64; 2], [u, w]: [[f64; 2]; 2]) -> Vec<[f64; 2]> {
+    (0..=16)
+        .map(|k| {
+            let (sin, cos) = (k as f64 * std::f64::consts::TAU / 16.).sin_cos();
+            [
+                c[0] + u[0] * cos + w[0] * sin,
+                c[1] + u[1] * cos + w[1] * sin,
+            ]
+        })
+        .collect()
+}
 
 impl eframe::App for ClickThrough {
     /// Called by the frame work to save state before shutdown.
-    fn save(&mut self, _storage: &mut dyn eframe::Storage) {
-        // eframe::set_value(storage, eframe::APP_KEY, self);
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        let current = self.sweep.as_ref().map(|sweep| sweep.identity());
+        if let Some(id) = &current {
+            self.sessions.insert(id.clone(), self.session.clone());
+        }
+        eframe::set_value(storage, SESSIONS_KEY, &self.sessions);
+        if let Some(id) = &current {
+            self.sessions.remove(id);
+        }
     }
 
     /// Called each time the UI needs repainting, which may be many times per second.
@@ -176,6 +1562,76 @@ impl eframe::App for ClickThrough {
         // Put your widgets into a `SidePanel`, `TopBottomPanel`, `CentralPanel`, `Window` or `Area`.
         // For inspiration and more examples, go to https://emilk.github.io/egui
 
+        #[allow(unused_mut)]
+        let mut dropped = ctx.input(|i| i.raw.dropped_files.clone());
+        // Native drops come as paths, web drops as bytes
+        #[cfg(not(target_arch = "wasm32"))]
+        if !dropped.is_empty() && dropped.iter().all(|f| f.path.is_some()) {
+            self.open_paths(dropped.drain(..).filter_map(|f| f.path).collect());
+        }
+        if !dropped.is_empty() {
+            let files = dropped
+                .into_iter()
+                .filter_map(|f| {
+                    Some((
+                        f.name,
+                        Source::Bytes {
+                            buffer: f.bytes?,
+                            cursor: 0,
+                        },
+                    ))
+                })
+                .collect();
+            self.open_files(files);
+        }
+
+        let mut dismissed = false;
+        if let Some(error) = &self.error {
+            egui::Window::new("Error")
+                .collapsible(false)
+                .resizable(false)
+                .anchor(egui::Align2::CENTER_CENTER, [0., 0.])
+                .show(ctx, |ui| {
+                    ui.label(error);
+                    dismissed = ui.button("OK").clicked();
+                });
+        }
+        if dismissed {
+            self.error = None;
+        }
+
+        if let Some(fitter) = &mut self.fitter {
+            fitter.poll();
+            if !fitter.pending.is_empty() {
+                // The worker can't wake the UI, so check back for its fits
+                ctx.request_repaint_after(std::time::Duration::from_millis(50));
+            }
+            let mut cancelled = false;
+            if let Some((path, total)) = &self.fits_export {
+                egui::Window::new("Exporting Fits")
+                    .collapsible(false)
+                    .resizable(false)
+                    .show(ctx, |ui| {
+                        let done = total - fitter.pending.len();
+                        ui.label(format!("Fitting for {}", path.display()));
+                        ui.add(
+                            egui::ProgressBar::new(done as f32 / *total as f32)
+                                .text(format!("{done} of {total}")),
+                        );
+                        cancelled = ui.button("Cancel").clicked();
+                    });
+            }
+            if cancelled {
+                fitter.cancel();
+                self.fits_export = None;
+            }
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        self.finish_fits_export();
+        if self.delays.finding() {
+            ctx.request_repaint_after(std::time::Duration::from_millis(50));
+        }
+
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
             // The top panel is often a good place for a menu bar:
 
@@ -184,43 +1640,492 @@ impl eframe::App for ClickThrough {
                 let is_web = cfg!(target_arch = "wasm32");
                 if !is_web {
                     ui.menu_button("File", |ui| {
+                        #[cfg(not(target_arch = "wasm32"))]
+                        if ui.button("Open…").clicked() {
+                            ui.close_menu();
+                            if let Some(paths) = rfd::FileDialog::new()
+                                .set_title("Open a power sweep config and values")
+                                .add_filter("Power sweep", &["json", "npz"])
+                                .pick_files()
+                            {
+                                self.open_paths(paths);
+                            }
+                        }
+                        #[cfg(not(target_arch = "wasm32"))]
+                        if ui
+                            .add_enabled(self.sweep.is_some(), egui::Button::new("Export…"))
+                            .on_hover_text("Export the bias settings, leaving out unaccepted suggestions")
+                            .clicked()
+                        {
+                            ui.close_menu();
+                            self.export();
+                        }
+                        #[cfg(not(target_arch = "wasm32"))]
+                        if ui
+                            .add_enabled(self.sweep.is_some(), egui::Button::new("Export Fits…"))
+                            .on_hover_text("Fit every resonator at every power, which can take a while")
+                            .clicked()
+                        {
+                            ui.close_menu();
+                            self.export_fits();
+                        }
+                        #[cfg(not(target_arch = "wasm32"))]
+                        if ui
+                            .add_enabled(self.sweep.is_some(), egui::Button::new("Export Review…"))
+                            .on_hover_text("Every resonator's state and note as CSV")
+                            .clicked()
+                        {
+                            ui.close_menu();
+                            self.export_review();
+                        }
+                        ui.separator();
+                        #[cfg(not(target_arch = "wasm32"))]
+                        if ui
+                            .add_enabled(self.sweep.is_some(), egui::Button::new("Import Reference…"))
+                            .clicked()
+                        {
+                            ui.close_menu();
+                            self.import_reference();
+                        }
+                        let has_reference = !self.session.reference.is_empty();
+                        if ui
+                            .add_enabled(has_reference, egui::Button::new("Use Reference Selections"))
+                            .on_hover_text("Select the reference setting for every resonator without a selection")
+                            .clicked()
+                        {
+                            ui.close_menu();
+                            if let Some(sweep) = &self.sweep {
+                                let edit = self.session.use_reference(sweep);
+                                self.history.push(&mut self.session, edit);
+                            }
+                        }
+                        if ui
+                            .add_enabled(has_reference, egui::Button::new("Clear Reference"))
+                            .clicked()
+                        {
+                            ui.close_menu();
+                            self.session.reference.clear();
+                        }
+                        ui.separator();
                         if ui.button("Quit").clicked() {
                             ctx.send_viewport_cmd(egui::ViewportCommand::Close);
                         }
                     });
                 }
 
+                ui.menu_button("Edit", |ui| {
+                    if ui
+                        .add_enabled(!self.history.done.is_empty(), egui::Button::new("Undo"))
+                        .clicked()
+                    {
+                        ui.close_menu();
+                        self.history.undo(&mut self.session);
+                    }
+                    if ui
+                        .add_enabled(!self.history.undone.is_empty(), egui::Button::new("Redo"))
+                        .clicked()
+                    {
+                        ui.close_menu();
+                        self.history.redo(&mut self.session);
+                    }
+                    ui.separator();
+                    let resonator = self.session.resonator;
+                    if ui
+                        .add_enabled(
+                            self.session.settings.contains_key(&resonator),
+                            egui::Button::new("Clear Current"),
+                        )
+                        .on_hover_text("Drop the current resonator's setting")
+                        .clicked()
+                    {
+                        ui.close_menu();
+                        let edit = self.session.clear([resonator]);
+                        self.history.push(&mut self.session, edit);
+                    }
+                    if ui
+                        .add_enabled(self.sweep.is_some(), egui::Button::new("Suggest All"))
+                        .on_hover_text(
+                            "Suggest a setting for every resonator without one, just below bifurcation",
+                        )
+                        .clicked()
+                    {
+                        ui.close_menu();
+                        self.suggest_all();
+                    }
+                });
+
                 ui.menu_button("View", |ui| {
+                    if ui.button("Resonator Table").clicked() {
+                        self.show_table = !self.show_table
+                    }
                     if ui.button("Bias Settings").clicked() {
                         self.show_settings = !self.show_settings
                     }
+                    if ui.button("History").clicked() {
+                        self.show_history = !self.show_history
+                    }
+                    if ui.button("Keyboard Shortcuts").clicked() {
+                        self.show_shortcuts = !self.show_shortcuts
+                    }
+                    if ui.button("Validation Report").clicked() {
+                        self.show_validation = !self.show_validation
+                    }
+                    if ui.button("Fit Results").clicked() {
+                        self.show_fits = !self.show_fits
+                    }
+                    if ui.button("Heat Map").clicked() {
+                        self.show_heat_map = !self.show_heat_map
+                    }
+                    if ui.button("Review Summary").clicked() {
+                        self.show_summary = !self.show_summary
+                    }
                 });
                 ui.add_space(16.0);
                 egui::widgets::global_theme_preference_buttons(ui);
             });
         });
 
+        egui::Window::new("Keyboard Shortcuts")
+            .open(&mut self.show_shortcuts)
+            .resizable(false)
+            .show(ctx, |ui| {
+                egui::Grid::new("shortcuts").striped(true).show(ui, |ui| {
+                    for (key, action) in SHORTCUTS {
+                        ui.strong(key);
+                        ui.label(action);
+                        ui.end_row();
+                    }
+                });
+            });
+
+        self.keys(ctx);
+
+        egui::Window::new("History")
+            .open(&mut self.show_history)
+            .scroll([false, true])
+            .show(ctx, |ui| {
+                if self.history.done.is_empty() && self.history.undone.is_empty() {
+                    ui.label("Nothing to undo");
+                }
+                for edit in &self.history.done {
+                    ui.label(edit.describe());
+                }
+                // Undone edits in the order they'd be redone
+                for edit in self.history.undone.iter().rev() {
+                    ui.add_enabled(false, Label::new(edit.describe()));
+                }
+            });
+
+        let (session, history) = (&mut self.session, &mut self.history);
+        let bulk_clear = &mut self.bulk_clear;
         egui::Window::new("Bias Settings")
             .open(&mut self.show_settings)
-            .scroll([false, true])
+            .default_height(400.)
             .show(ctx, |ui| {
-                ui.add(
-                    Label::new(serde_json::to_string_pretty(&self.settings).unwrap())
-                        .selectable(true)
-                        .extend(),
-                )
+                ui.horizontal(|ui| {
+                    egui::ComboBox::from_id_salt("bulk clear")
+                        .selected_text(if bulk_clear.above { "Above" } else { "Below" })
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut bulk_clear.above, true, "Above");
+                            ui.selectable_value(&mut bulk_clear.above, false, "Below");
+                        });
+                    ui.add(
+                        egui::DragValue::new(&mut bulk_clear.atten)
+                            .speed(0.25)
+                            .suffix(" dB"),
+                    );
+                    let matching: Vec<usize> = session
+                        .settings
+                        .iter()
+                        .filter(|(_, s)| bulk_clear.matches(s))
+                        .map(|(r, _)| *r)
+                        .collect();
+                    if ui
+                        .add_enabled(
+                            !matching.is_empty(),
+                            egui::Button::new(format!("Clear {}", matching.len())),
+                        )
+                        .on_hover_text("Clear every selection with output attenuation past this")
+                        .clicked()
+                    {
+                        let edit = session.clear(matching);
+                        history.push(session, edit);
+                    }
+                });
+                ui.collapsing("JSON", |ui| {
+                    egui::ScrollArea::vertical()
+                        .id_salt("settings json")
+                        .max_height(200.)
+                        .show(ui, |ui| {
+                            ui.add(
+                                Label::new(
+                                    serde_json::to_string_pretty(&session.settings).unwrap(),
+                                )
+                                .selectable(true)
+                                .extend(),
+                            )
+                        });
+                });
+                ui.separator();
+
+                // Only the rows in view are laid out, there can be thousands
+                let mut resonators: Vec<usize> = session.settings.keys().copied().collect();
+                resonators.sort_unstable();
+                let height = ui.text_style_height(&egui::TextStyle::Body) + 4.;
+                let widths = [60., 100., 100.];
+                ui.horizontal(|ui| {
+                    for (heading, width) in ["#", "Atten", "Tone (MHz)"].into_iter().zip(widths) {
+                        ui.add_sized(
+                            [width, height],
+                            Label::new(egui::RichText::new(heading).strong()),
+                        );
+                    }
+                });
+                let mut cleared = None;
+                egui::ScrollArea::vertical().auto_shrink(false).show_rows(
+                    ui,
+                    height,
+                    resonators.len(),
+                    |ui, range| {
+                        for r in resonators[range].iter().copied() {
+                            let setting = &session.settings[&r];
+                            let cells = [
+                                r.to_string(),
+                                format!("{}/{} dB", setting.output_atten, setting.input_atten),
+                                format!("{:.4}", setting.freq / 1e6),
+                            ];
+                            ui.horizontal(|ui| {
+                                for (text, width) in cells.into_iter().zip(widths) {
+                                    ui.add_sized([width, height], Label::new(text));
+                                }
+                                if ui.small_button("Clear").clicked() {
+                                    cleared = Some(r);
+                                }
+                            });
+                        }
+                    },
+                );
+                if let Some(r) = cleared {
+                    let edit = session.clear([r]);
+                    history.push(session, edit);
+                }
+            });
+
+        if let Some(sweep) = &self.sweep {
+            egui::Window::new("Validation Report")
+                .open(&mut self.show_validation)
+                .show(ctx, |ui| {
+                    if sweep.report.is_clean() {
+                        ui.label("The sweep matches its config");
+                    } else {
+                        ui.label(sweep.report.to_string());
+                    }
+                });
+
+            let n = sweep.values.iq[0].1.nrows();
+            let unreviewed = (0..n)
+                .filter(|r| self.session.state(*r) == ResonatorState::Unreviewed)
+                .count();
+            if unreviewed == 0 && self.unreviewed.is_some_and(|u| u > 0) {
+                self.show_summary = true;
+            }
+            self.unreviewed = Some(unreviewed);
+            let session = &mut self.session;
+            let summary_excluded = &mut self.summary_excluded;
+            egui::Window::new("Review Summary")
+                .open(&mut self.show_summary)
+                .scroll([false, true])
+                .show(ctx, |ui| {
+                    let counts: Vec<String> = ResonatorState::ALL
+                        .iter()
+                        .map(|state| {
+                            let count = (0..n).filter(|r| session.state(*r) == *state).count();
+                            format!("{count} {}", state.label().to_lowercase())
+                        })
+                        .collect();
+                    ui.label(format!("{n} resonators: {}", counts.join(", ")));
+                    ui.checkbox(summary_excluded, "Include bad and collided");
+                    let unselected: Vec<usize> = session
+                        .unselected(n)
+                        .into_iter()
+                        .filter(|r| {
+                            *summary_excluded
+                                || !matches!(
+                                    session.state(*r),
+                                    ResonatorState::Bad | ResonatorState::Collision
+                                )
+                        })
+                        .collect();
+                    if unselected.is_empty() {
+                        ui.label("Every resonator has a selection");
+                        return;
+                    }
+                    ui.label(format!("{} without a selection", unselected.len()));
+                    ui.separator();
+                    egui::Grid::new("summary").striped(true).show(ui, |ui| {
+                        for r in unselected {
+                            ui.label(r.to_string());
+                            let state = match session.state(r) {
+                                ResonatorState::Unreviewed if session.suggested.contains(&r) => {
+                                    "Suggested"
+                                }
+                                state => state.label(),
+                            };
+                            ui.label(state);
+                            ui.label(session.notes.get(&r).map_or("", String::as_str));
+                            if ui
+                                .add_enabled(session.resonator != r, egui::Button::new("Go"))
+                                .clicked()
+                            {
+                                session.resonator = r;
+                            }
+                            ui.end_row();
+                        }
+                    });
+                });
+
+            let resonator = self.session.resonator;
+            egui::Window::new(format!("Fit Results, Resonator {resonator}"))
+                .id(Id::new("Fit Results"))
+                .open(&mut self.show_fits)
+                .scroll([false, true])
+                .show(ctx, |ui| {
+                    egui::Grid::new("fits").striped(true).show(ui, |ui| {
+                        for heading in ["Atten", "f0 (MHz)", "Qi", "Qc", "a", "Residual"] {
+                            ui.strong(heading);
+                        }
+                        ui.end_row();
+                        for (ai, ((o, i), _)) in sweep.values.iq.iter().enumerate() {
+                            ui.label(format!("{o}/{i} dB"));
+                            match self.fitter.as_mut().and_then(|f| f.get((resonator, ai))) {
+                                Some(Some(fit)) => {
+                                    ui.label(format!("{:.4}", fit.f0 / 1e6));
+                                    ui.label(format!("{:.0}", fit.qi));
+                                    ui.label(format!("{:.0}", fit.qc));
+                                    ui.label(format!("{:.3}", fit.a));
+                                    ui.label(format!("{:.1e}", fit.residual));
+                                }
+                                Some(None) => {
+                                    ui.label("Didn't converge");
+                                }
+                                None => {
+                                    ui.spinner();
+                                }
+                            }
+                            ui.end_row();
+                        }
+                    });
+                });
+        }
+
+        if self.show_table && self.sweep.is_some() {
+            egui::SidePanel::left("resonators")
+                .resizable(true)
+                .default_width(480.)
+                .show(ctx, |ui| self.table(ui));
+        }
+
+        let Some(sweep) = &self.sweep else {
+            egui::CentralPanel::default().show(ctx, |ui| {
+                ui.centered_and_justified(|ui| {
+                    ui.label("Open a power sweep with File → Open…, or drop its .json config and .npz values here");
+                });
+            });
+            return;
+        };
+
+        let mut heat_clicked = None;
+        let resonator = self.session.resonator;
+        egui::Window::new(format!("Heat Map, Resonator {resonator}"))
+            .id(Id::new("Heat Map"))
+            .open(&mut self.show_heat_map)
+            .default_size([480., 360.])
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.selectable_value(&mut self.heat_map, HeatMap::Magnitude, "|S21| (dB)")
+                        .on_hover_text("Raw |S21|, not delay or baseline corrected");
+                    ui.selectable_value(&mut self.heat_map, HeatMap::Velocity, "IQ Velocity");
+                });
+                let key = (resonator, self.heat_map, self.normalise, self.session.gamma);
+                let texture = match &self.heat_texture {
+                    Some((k, texture)) if *k == key => texture,
+                    _ => {
+                        let image = heat_map(
+                            sweep,
+                            resonator,
+                            self.heat_map,
+                            self.normalise,
+                            self.session.gamma,
+                        );
+                        // Nearest, so each band shows its own row and steps stay distinct
+                        let texture =
+                            ctx.load_texture("heat map", image, egui::TextureOptions::NEAREST);
+                        &self.heat_texture.insert((key, texture)).1
+                    }
+                };
+
+                // Pixels are centered on the steps, which are evenly spaced, and each
+                // attenuation gets its own band since some may be missing or repeated
+                let steps = &sweep.config.sweep_config.steps;
+                let attens: Vec<f64> = sweep.values.iq.iter().map(|((o, _), _)| *o).collect();
+                let bands = bands(&attens);
+                let df = ((steps[steps.len() - 1] - steps[0]) / (steps.len() as f64 - 1.).max(1.))
+                    .max(f64::MIN_POSITIVE);
+                let width = df * steps.len() as f64;
+                let x = steps[0] - df / 2. + width / 2.;
+                Plot::new("Heat Map")
+                    .show_axes([true, true])
+                    .allow_zoom(false)
+                    .allow_scroll(false)
+                    .allow_drag(false)
+                    .show(ui, |plotui| {
+                        // The image has the highest attenuation in its top row
+                        let n = attens.len() as f32;
+                        for (ai, [low, high]) in bands.iter().enumerate() {
+                            let row = (n - 1. - ai as f32) / n;
+                            plotui.image(
+                                egui_plot::PlotImage::new(
+                                    texture,
+                                    egui_plot::PlotPoint::new(x, (low + high) / 2.),
+                                    [width as f32, (high - low) as f32],
+                                )
+                                .uv(egui::Rect::from_min_max(
+                                    egui::pos2(0., row),
+                                    egui::pos2(1., row + 1. / n),
+                                )),
+                            );
+                        }
+                        if plotui.response().clicked() {
+                            heat_clicked = plotui.pointer_coordinate().and_then(|p| {
+                                let atten = bands
+                                    .iter()
+                                    .position(|[low, high]| p.y >= *low && p.y < *high)?;
+                                let freq = ((p.x - steps[0]) / df).round();
+                                (freq >= 0. && (freq as usize) < steps.len()).then_some(BiasPoint {
+                                    atten,
+                                    freq: freq as usize,
+                                })
+                            });
+                        }
+                    });
             });
 
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.horizontal(|ui| {
                 ui.spacing_mut().slider_width = ui.available_width() / 3.;
                 ui.add(
-                    egui::Slider::new(&mut self.freq_range.0, self.freq_max.0..=self.freq_range.1)
-                        .clamping(egui::SliderClamping::Always),
+                    egui::Slider::new(
+                        &mut self.session.freq_range.0,
+                        self.freq_max.0..=self.session.freq_range.1,
+                    )
+                    .clamping(egui::SliderClamping::Always),
                 );
                 ui.add(
-                    egui::Slider::new(&mut self.freq_range.1, self.freq_range.0..=self.freq_max.1)
-                        .clamping(egui::SliderClamping::Always),
+                    egui::Slider::new(
+                        &mut self.session.freq_range.1,
+                        self.session.freq_range.0..=self.freq_max.1,
+                    )
+                    .clamping(egui::SliderClamping::Always),
                 );
                 ui.label("Frequency Range");
             });
@@ -228,157 +2133,662 @@ impl eframe::App for ClickThrough {
                 ui.spacing_mut().slider_width = ui.available_width() / 3.;
                 ui.add(
                     egui::Slider::new(
-                        &mut self.atten_range.0,
-                        self.atten_max.0..=self.atten_range.1,
+                        &mut self.session.atten_range.0,
+                        self.atten_max.0..=self.session.atten_range.1,
                     )
                     .clamping(egui::SliderClamping::Always),
                 );
                 ui.add(
                     egui::Slider::new(
-                        &mut self.atten_range.1,
-                        self.atten_range.0..=self.atten_max.1,
+                        &mut self.session.atten_range.1,
+                        self.session.atten_range.0..=self.atten_max.1,
                     )
                     .clamping(egui::SliderClamping::Always),
                 );
                 ui.label("Attenuation Range");
             });
+            let mut onset = None;
             ui.horizontal(|ui| {
                 ui.add(
-                    egui::Slider::new(&mut self.resonator, 0..=self.values.iq[0].1.shape()[0] - 1)
-                        .clamping(egui::SliderClamping::Always)
-                        .text("Resonator"),
+                    egui::Slider::new(
+                        &mut self.session.resonator,
+                        0..=sweep.values.iq[0].1.shape()[0] - 1,
+                    )
+                    .clamping(egui::SliderClamping::Always)
+                    .text("Resonator"),
+                );
+                ui.add(egui::Separator::default());
+                ui.add(egui::Slider::new(&mut self.session.gamma, 0.0..=3.0).text("Gamma"));
+                ui.add(
+                    egui::DragValue::new(&mut self.session.line_atten)
+                        .speed(0.5)
+                        .suffix(" dB"),
+                )
+                .on_hover_text(
+                    "Fixed attenuation of the lines down to the chip, for the tone power of new selections",
+                );
+                ui.label("Line");
+                ui.add_enabled(
+                    sweep.values.iqs.is_some(),
+                    egui::Checkbox::new(&mut self.show_rms, "RMS"),
+                );
+                ui.checkbox(&mut self.canonical, "Canonical").on_hover_text(
+                    "Fit a circle to each loop and turn it to sit off resonance at 1",
                 );
+                egui::ComboBox::from_id_salt("loops")
+                    .selected_text(self.loops.label())
+                    .show_ui(ui, |ui| {
+                        for loops in [Loops::Raw, Loops::DatasetDelay, Loops::ResonatorDelay] {
+                            ui.selectable_value(&mut self.loops, loops, loops.label());
+                        }
+                    })
+                    .response
+                    .on_hover_text("Take the cable delay and baseline out of the loops");
+                match self.delays.get(sweep, self.loops, self.session.resonator) {
+                    Some(tau) => {
+                        ui.label(format!("{:.2} ns", tau * 1e9));
+                    }
+                    // Left raw until the sweep's delay is back
+                    None if self.delays.finding() => {
+                        ui.spinner();
+                    }
+                    None => {}
+                }
+                ui.checkbox(&mut self.show_fit, "Fit")
+                    .on_hover_text("Overlay the nonlinear resonator model fitted at each power");
+                ui.add(egui::Separator::default());
+                egui::ComboBox::from_id_salt("normalise")
+                    .selected_text(self.normalise.label())
+                    .show_ui(ui, |ui| {
+                        for normalise in [Normalise::None, Normalise::Gain, Normalise::OffResonance]
+                        {
+                            ui.selectable_value(&mut self.normalise, normalise, normalise.label());
+                        }
+                    })
+                    .response
+                    .on_hover_text("Normalise the magnitude plot and heat map");
+                ui.checkbox(&mut self.mag_db, "dB");
                 ui.add(egui::Separator::default());
-                ui.add(egui::Slider::new(&mut self.gamma, 0.0..=3.0).text("Gamma"));
+                ui.checkbox(&mut self.show_onset, "Onset")
+                    .on_hover_text("Mark the highest power not yet bifurcated");
+                if self.show_onset {
+                    onset = analysis::onset(&sweep.values, self.session.resonator);
+                    match onset {
+                        Some(ai) => ui.label(format!("{} dB", sweep.values.iq[ai].0 .0)),
+                        None => ui.label("Bifurcated at every power"),
+                    };
+                }
+            });
+            ui.horizontal(|ui| {
+                let resonator = self.session.resonator;
+                ui.label(format!("State: {}", self.session.state(resonator).label()));
+                for (state, label, hover) in [
+                    (
+                        ResonatorState::Skipped,
+                        "Skip",
+                        "Leave without a setting for now (S)",
+                    ),
+                    (
+                        ResonatorState::Bad,
+                        "Bad",
+                        "Dead or unusable, no setting (B)",
+                    ),
+                    (
+                        ResonatorState::Collision,
+                        "Collision",
+                        "Collided with or a double of a neighbour, no setting (C)",
+                    ),
+                    (
+                        ResonatorState::NeedsReview,
+                        "Needs Review",
+                        "Come back to this one, keeping any setting (R)",
+                    ),
+                ] {
+                    if ui.button(label).on_hover_text(hover).clicked() {
+                        let edit = self.session.mark(sweep, state);
+                        self.history.push(&mut self.session, edit);
+                    }
+                }
+                ui.add(egui::Separator::default());
+                ui.label("Note");
+                let mut note = self
+                    .session
+                    .notes
+                    .get(&resonator)
+                    .cloned()
+                    .unwrap_or_default();
+                if ui
+                    .add(egui::TextEdit::singleline(&mut note).desired_width(f32::INFINITY))
+                    .changed()
+                {
+                    if note.is_empty() {
+                        self.session.notes.remove(&resonator);
+                    } else {
+                        self.session.notes.insert(resonator, note);
+                    }
+                }
             });
+            if !self.session.suggested.is_empty() {
+                ui.horizontal(|ui| {
+                    let resonator = self.session.resonator;
+                    let next = self
+                        .session
+                        .next_unreviewed(resonator, sweep.values.iq[0].1.nrows());
+                    if self.session.suggested.contains(&resonator) {
+                        ui.label("Suggested, click the plot to nudge it or");
+                        if ui.button("Accept").clicked() {
+                            let setting = self.session.settings.get(&resonator).cloned();
+                            let change = Change::new(&self.session, resonator, setting, false);
+                            let edit =
+                                Edit::new(&self.session, EditKind::Accept, vec![change], next);
+                            self.history.push(&mut self.session, edit);
+                        }
+                        if ui
+                            .button("Reject")
+                            .on_hover_text("Drop the suggestion, leaving it unreviewed")
+                            .clicked()
+                        {
+                            let change = Change::new(&self.session, resonator, None, false);
+                            let edit =
+                                Edit::new(&self.session, EditKind::Delete, vec![change], next);
+                            self.history.push(&mut self.session, edit);
+                        }
+                        ui.add(egui::Separator::default());
+                    }
+                    ui.label(format!(
+                        "{} suggestions to review",
+                        self.session.suggested.len()
+                    ));
+                    let next = self
+                        .session
+                        .suggested
+                        .iter()
+                        .filter(|r| **r > resonator)
+                        .min()
+                        .or(self.session.suggested.iter().min());
+                    if let Some(next) = next {
+                        if ui.button("Next Suggestion").clicked() {
+                            self.session.resonator = *next;
+                        }
+                    }
+                });
+            }
 
             let h = ui.available_height();
             ui.horizontal(|ui| {
                 ui.set_height(h);
-                let iqs: Vec<IQs> = self
+                let in_range = |f: usize| {
+                    let f = sweep.config.sweep_config.steps[f];
+                    f >= self.session.freq_range.0 && f <= self.session.freq_range.1
+                };
+                let resonator = self.session.resonator;
+                let freqs = sweep.config.sweep_config.tone_freqs(resonator);
+                let delay = self.delays.get(sweep, self.loops, resonator);
+                let correction = |ai: usize| {
+                    let row = sweep.values.iq[ai].1.slice(s![resonator, ..]);
+                    delay.map(|delay| Correction::new(&freqs, row, delay))
+                };
+                // Maps a point of the sweep at `values.iq[ai]` onto the plots, multiplying it
+                // by `gain` unless it's corrected
+                let transform = |ai: usize, gain: f64| {
+                    let iq = &sweep.values.iq[ai].1;
+                    let (correction, freqs) = (correction(ai), &freqs);
+                    let corrected = move |f: usize, v: Complex<f64>| match &correction {
+                        Some(c) => c.apply(freqs[f], v),
+                        None => v * gain,
+                    };
+                    // Fitted to the whole loop so it doesn't move with the frequency range
+                    let canonical = self
+                        .canonical
+                        .then(|| {
+                            let points: Vec<Complex<f64>> = iq
+                                .slice(s![resonator, ..])
+                                .iter()
+                                .enumerate()
+                                .map(|(f, v)| corrected(f, Complex::new(v.re as f64, v.im as f64)))
+                                .collect();
+                            Canonical::new(&points)
+                        })
+                        .flatten();
+                    move |f: usize, v: Complex<f64>| {
+                        let z = corrected(f, v);
+                        canonical.map_or(z, |c| c.apply(z))
+                    }
+                };
+                let iqs: Vec<IQs> = sweep
                     .values
                     .iq
                     .iter()
                     .enumerate()
-                    .filter(|(_, ((o, _), _))| *o >= self.atten_range.0 && *o <= self.atten_range.1)
-                    .map(|(ai, ((o, i), iq))| {
-                        let gain = (10f64.powf((*i + *o * self.gamma) / 10.)).sqrt();
+                    .filter(|(_, ((o, _), _))| {
+                        *o >= self.session.atten_range.0 && *o <= self.session.atten_range.1
+                    })
+                    .map(|(ai, (a @ (o, i), iq))| {
+                        let unscaled = transform(ai, 1.);
+                        let transform = transform(ai, gain(*i, *o, self.session.gamma));
+                        let rms = sweep.values.rms(*a).filter(|_| self.show_rms);
+                        let row = iq.slice(s![resonator, ..]);
+                        // From the same correction as the loop, but normalised on its own
+                        let magnitudes: Vec<f64> = row
+                            .iter()
+                            .enumerate()
+                            .map(|(f, v)| {
+                                unscaled(f, Complex::new(v.re as f64, v.im as f64)).norm()
+                            })
+                            .collect();
+                        let scale = self.normalise.scale(*a, self.session.gamma, &magnitudes);
+                        let magnitude = |m: f64| {
+                            let m = m * scale;
+                            if self.mag_db {
+                                20. * m.log10()
+                            } else {
+                                m
+                            }
+                        };
+                        let points: Vec<(usize, Complex<f64>)> = iq
+                            .slice(s![resonator, ..])
+                            .iter()
+                            .enumerate()
+                            .filter(|(f, _)| in_range(*f))
+                            .map(|(f, v)| (f, transform(f, Complex::new(v.re as f64, v.im as f64))))
+                            .collect();
                         (
                             ai,
                             *o,
-                            iq.slice(s![self.resonator, ..])
-                                .iter()
-                                .enumerate()
-                                .filter(|(f, _)| {
-                                    let f = self.psweep.sweep_config.steps[*f];
-                                    f >= self.freq_range.0 && f <= self.freq_range.1
-                                })
-                                .map(|(_, v)| [v.re as f64 * gain, v.im as f64 * gain])
-                                .collect(),
-                            iq.slice(s![self.resonator, ..])
+                            points.iter().map(|(_, v)| [v.re, v.im]).collect(),
+                            magnitudes
                                 .iter()
                                 .enumerate()
-                                .filter(|(f, _)| {
-                                    let f = self.psweep.sweep_config.steps[*f];
-                                    f >= self.freq_range.0 && f <= self.freq_range.1
-                                })
-                                .map(|(f, c)| {
-                                    [self.psweep.sweep_config.steps[f], gain * c.abs() as f64]
-                                })
+                                .filter(|(f, _)| in_range(*f))
+                                .map(|(f, m)| [sweep.config.sweep_config.steps[f], magnitude(*m)])
                                 .collect(),
+                            rms.map(|rms| {
+                                rms.slice(s![resonator, ..])
+                                    .iter()
+                                    .zip(iq.slice(s![resonator, ..]))
+                                    .enumerate()
+                                    .filter(|(f, _)| in_range(*f))
+                                    .map(|(f, (r, v))| {
+                                        // The transform is affine at each step, so its slope
+                                        // rotates and scales the I and Q axes like the loop
+                                        let v = Complex::new(v.re as f64, v.im as f64);
+                                        let slope = transform(f, v + 1.) - transform(f, v);
+                                        let u = slope * r.re as f64;
+                                        let w = slope * Complex::i() * r.im as f64;
+                                        [[u.re, u.im], [w.re, w.im]]
+                                    })
+                                    .collect()
+                            })
+                            .unwrap_or_default(),
                         )
                     })
                     .collect();
 
-                let fmap: Vec<usize> = self
-                    .psweep
+                let fmap: Vec<usize> = sweep
+                    .config
                     .sweep_config
                     .steps
                     .iter()
                     .enumerate()
-                    .filter(|(_, f)| **f >= self.freq_range.0 && **f <= self.freq_range.1)
+                    .filter(|(_, f)| {
+                        **f >= self.session.freq_range.0 && **f <= self.session.freq_range.1
+                    })
                     .map(|(fi, _)| fi)
                     .collect();
 
+                // Where a bias point ended up in the loop and magnitude plots
+                let plotted = |p: BiasPoint| {
+                    let num = fmap.iter().position(|f| *f == p.freq)?;
+                    let (_, _, l, m, _) = iqs.iter().find(|(ai, ..)| *ai == p.atten)?;
+                    Some((l[num], m[num]))
+                };
+                let reference = self
+                    .session
+                    .reference
+                    .get(&self.session.resonator)
+                    .and_then(|s| bias_point(sweep, self.session.resonator, s));
+                let reference_color = Color32::RED;
+                let selected = self
+                    .session
+                    .settings
+                    .get(&self.session.resonator)
+                    .and_then(|s| bias_point(sweep, self.session.resonator, s));
+                let (selected_color, selected_name) =
+                    if self.session.suggested.contains(&self.session.resonator) {
+                        (Color32::ORANGE, "Suggested")
+                    } else {
+                        (Color32::from_rgb(255, 0, 255), "Selected")
+                    };
+
+                let width = |ai: usize| if onset == Some(ai) { 4. } else { 1.5 };
+
+                // Fitted loops scaled the same way as the data
+                let mut models: HashMap<usize, Vec<[f64; 2]>> = HashMap::new();
+                if self.show_fit {
+                    for (ai, ..) in iqs.iter() {
+                        let Some(Some(fit)) =
+                            self.fitter.as_mut().and_then(|f| f.get((resonator, *ai)))
+                        else {
+                            continue;
+                        };
+                        let (o, i) = sweep.values.iq[*ai].0;
+                        let transform = transform(*ai, gain(i, o, self.session.gamma));
+                        let model = fmap
+                            .iter()
+                            .map(|f| {
+                                let s = transform(*f, fit.s21(freqs[*f]));
+                                [s.re, s.im]
+                            })
+                            .collect();
+                        models.insert(*ai, model);
+                    }
+                }
+
                 let mut ids: HashMap<Id, BiasPoint> =
-                    HashMap::with_capacity(self.values.iq.len() * 1024);
+                    HashMap::with_capacity(sweep.values.iq.len() * 1024);
+                let linked = self.linked_hover;
+                let cursor = self
+                    .cursor
+                    .filter(|(r, _)| *r == self.session.resonator)
+                    .map(|(_, bp)| bp);
+                let cursor_color = Color32::LIGHT_BLUE;
+                let hover_color = ui.visuals().strong_text_color();
 
-                let pr = Plot::new(format!("Clickey{}{}", self.resonator, self.gamma))
-                    .show_axes([false, false])
-                    .width(ui.available_width() / 2.)
-                    .data_aspect(1.0)
-                    .auto_bounds([true, true].into())
-                    .show(ui, |plotui| {
-                        iqs.iter().for_each(|(ai, o, l, _)| {
-                            let t = (*o - self.atten_range.0)
-                                / (self.atten_range.1 - self.atten_range.0);
-                            let t = 1. - t;
-                            let color = VIRIDIS.eval_continuous(t);
-                            let color = Color32::from_rgb(color.r, color.g, color.b);
-                            for (num, point) in l.iter().enumerate() {
-                                let bp = BiasPoint {
-                                    output_atten: *ai,
-                                    freq: fmap[num],
-                                };
-                                let id = Id::new(bp);
-                                ids.insert(id, bp);
-                                plotui.points(
-                                    Points::new(vec![*point]).color(color).radius(4.).id(id),
-                                )
-                            }
-                            plotui.line(Line::new(l.clone()).color(color).allow_hover(false))
-                        })
+                let pr = Plot::new(format!(
+                    "Clickey{}{}",
+                    self.session.resonator, self.session.gamma
+                ))
+                .show_axes([false, false])
+                .width(ui.available_width() / 2.)
+                .data_aspect(1.0)
+                .auto_bounds([true, true].into())
+                .show(ui, |plotui| {
+                    iqs.iter().for_each(|(ai, _, l, _, rms)| {
+                        let color = colour(sweep, self.session.atten_range, *ai);
+                        for (point, r) in l.iter().zip(rms.iter()) {
+                            plotui.line(
+                                Line::new(ellipse(*point, *r))
+                                    .color(color.gamma_multiply(0.5))
+                                    .allow_hover(false),
+                            )
+                        }
+                        for (num, point) in l.iter().enumerate() {
+                            let bp = BiasPoint {
+                                atten: *ai,
+                                freq: fmap[num],
+                            };
+                            let id = Id::new(bp);
+                            ids.insert(id, bp);
+                            plotui.points(Points::new(vec![*point]).color(color).radius(4.).id(id))
+                        }
+                        plotui.line(
+                            Line::new(l.clone())
+                                .color(color)
+                                .width(width(*ai))
+                                .allow_hover(false),
+                        );
+                        if let Some(model) = models.get(ai) {
+                            plotui.line(
+                                Line::new(model.clone())
+                                    .color(color)
+                                    .style(LineStyle::dashed_dense())
+                                    .allow_hover(false),
+                            )
+                        }
                     });
+                    if let Some((point, _)) = reference.and_then(plotted) {
+                        plotui.points(marker(
+                            point,
+                            MarkerShape::Diamond,
+                            reference_color,
+                            "Reference",
+                        ))
+                    }
+                    if let Some((point, _)) = selected.and_then(plotted) {
+                        plotui.points(marker(
+                            point,
+                            MarkerShape::Circle,
+                            selected_color,
+                            selected_name,
+                        ))
+                    }
+                    if let Some((point, _)) = linked.and_then(plotted) {
+                        plotui.points(marker(point, MarkerShape::Circle, hover_color, "Hovered"))
+                    }
+                    if let Some((point, _)) = cursor.and_then(plotted) {
+                        plotui.points(marker(point, MarkerShape::Square, cursor_color, "Cursor"))
+                    }
+                });
 
-                let mut bp = None;
-                if let Some(h) = pr.hovered_plot_item {
-                    bp = ids.get(&h);
-                }
+                let clicked = pr.hovered_plot_item.and_then(|h| ids.get(&h)).copied();
+                let bp = clicked.or(linked);
 
-                Plot::new("Showey")
-                    .show_axes([true, true])
-                    .width(ui.available_width())
-                    .allow_zoom(false)
-                    .allow_scroll(false)
-                    .allow_drag(false)
-                    .show(ui, |plotui| {
-                        if self.show_mag {
-                            iqs.iter().for_each(|(_, o, _, l)| {
-                                let t = (*o - self.atten_range.0)
-                                    / (self.atten_range.1 - self.atten_range.0);
-                                let t = 1. - t;
-                                let color = VIRIDIS.eval_continuous(t);
-                                let mut color = Color32::from_rgb(color.r, color.g, color.b);
-                                if bp.is_some() {
-                                    color = color.gamma_multiply(0.1);
+                let (hovered, phase_clicked) = ui
+                    .vertical(|ui| {
+                        let h = ui.available_height() / 3.;
+                        Plot::new("Showey")
+                            .show_axes([true, true])
+                            .width(ui.available_width())
+                            .height(h)
+                            .allow_zoom(false)
+                            .allow_scroll(false)
+                            .allow_drag(false)
+                            .show(ui, |plotui| {
+                                if self.show_mag {
+                                    iqs.iter().for_each(|(ai, _, iq, l, rms)| {
+                                        let mut color =
+                                            colour(sweep, self.session.atten_range, *ai);
+                                        if bp.is_some() {
+                                            color = color.gamma_multiply(0.1);
+                                        }
+                                        // Propagate the I and Q RMS through to the magnitude
+                                        for ((p, [u, w]), [f, m]) in
+                                            iq.iter().zip(rms.iter()).zip(l.iter())
+                                        {
+                                            // Relative error, as the magnitude may be scaled,
+                                            // from each axis' component along the point
+                                            let along = |a: &[f64; 2]| a[0] * p[0] + a[1] * p[1];
+                                            let e =
+                                                along(u).hypot(along(w)) / p[0].hypot(p[1]).powi(2);
+                                            let e = if self.mag_db {
+                                                20. / std::f64::consts::LN_10 * e
+                                            } else {
+                                                m * e
+                                            };
+                                            plotui.line(
+                                                Line::new(vec![[*f, m - e], [*f, m + e]])
+                                                    .color(color.gamma_multiply(0.5))
+                                                    .allow_hover(false),
+                                            )
+                                        }
+                                        plotui.line(
+                                            Line::new(l.clone())
+                                                .color(color)
+                                                .width(width(*ai))
+                                                .allow_hover(false),
+                                        )
+                                    });
+                                }
+                                if let Some(r) = reference {
+                                    plotui.vline(
+                                        VLine::new(sweep.config.sweep_config.steps[r.freq])
+                                            .color(reference_color)
+                                            .style(LineStyle::dashed_loose())
+                                            .allow_hover(false),
+                                    );
+                                    if let Some((_, point)) = plotted(r) {
+                                        plotui.points(marker(
+                                            point,
+                                            MarkerShape::Diamond,
+                                            reference_color,
+                                            "Reference",
+                                        ))
+                                    }
+                                }
+                                if let Some((_, point)) = selected.and_then(plotted) {
+                                    plotui.points(marker(
+                                        point,
+                                        MarkerShape::Circle,
+                                        selected_color,
+                                        selected_name,
+                                    ))
+                                }
+                                if let Some((_, point)) = cursor.and_then(plotted) {
+                                    plotui.points(marker(
+                                        point,
+                                        MarkerShape::Square,
+                                        cursor_color,
+                                        "Cursor",
+                                    ))
+                                }
+                                if let Some(bp) = bp {
+                                    plotui.vline(VLine::new(
+                                        sweep.config.sweep_config.steps[bp.freq],
+                                    ));
+                                    for (ai, _, _, v, _) in iqs.iter() {
+                                        if *ai == bp.atten {
+                                            plotui.line(
+                                                Line::new(v.clone()).highlight(self.show_mag),
+                                            );
+                                            break;
+                                        }
+                                    }
+                                    plotui.set_auto_bounds([true, true].into());
                                 }
-                                plotui.line(Line::new(l.clone()).color(color).allow_hover(false))
                             });
-                        }
-                        if let Some(bp) = bp {
-                            plotui.vline(VLine::new(self.psweep.sweep_config.steps[bp.freq]));
-                            for (ai, _, _, v) in iqs.iter() {
-                                if *ai == bp.output_atten {
-                                    plotui.line(Line::new(v.clone()).highlight(self.show_mag));
-                                    break;
+
+                        // Distance moved in the IQ plane per MHz, plotted between the two steps
+                        let vr = Plot::new("Velocity")
+                            .show_axes([true, true])
+                            .width(ui.available_width())
+                            .height(h)
+                            .allow_zoom(false)
+                            .allow_scroll(false)
+                            .allow_drag(false)
+                            .show(ui, |plotui| {
+                                let steps = &sweep.config.sweep_config.steps;
+                                let velocity = |l: &[[f64; 2]]| -> Vec<[f64; 2]> {
+                                    l.windows(2)
+                                        .zip(fmap.windows(2))
+                                        .map(|(p, f)| {
+                                            let (a, b) = (steps[f[0]], steps[f[1]]);
+                                            let d = (p[1][0] - p[0][0]).hypot(p[1][1] - p[0][1]);
+                                            [(a + b) / 2., d / (b - a).abs().max(f64::MIN_POSITIVE)]
+                                        })
+                                        .collect()
+                                };
+                                iqs.iter().for_each(|(ai, _, l, _, _)| {
+                                    let mut color = colour(sweep, self.session.atten_range, *ai);
+                                    if bp.is_some() {
+                                        color = color.gamma_multiply(0.1);
+                                    }
+                                    let v = velocity(l);
+                                    // Each point stands for the step it arrives at
+                                    for (num, point) in v.iter().enumerate() {
+                                        let bp = BiasPoint {
+                                            atten: *ai,
+                                            freq: fmap[num + 1],
+                                        };
+                                        let id = Id::new(("velocity", bp));
+                                        ids.insert(id, bp);
+                                        plotui.points(
+                                            Points::new(vec![*point])
+                                                .color(color)
+                                                .radius(2.)
+                                                .id(id),
+                                        )
+                                    }
+                                    plotui.line(
+                                        Line::new(v)
+                                            .color(color)
+                                            .width(width(*ai))
+                                            .allow_hover(false),
+                                    )
+                                });
+                                if let Some(bp) = bp {
+                                    plotui.vline(VLine::new(steps[bp.freq]));
+                                    if let Some((_, _, l, _, _)) =
+                                        iqs.iter().find(|(ai, ..)| *ai == bp.atten)
+                                    {
+                                        plotui.line(Line::new(velocity(l)).highlight(true));
+                                    }
                                 }
-                            }
-                            plotui.set_auto_bounds([true, true].into());
-                        }
-                    });
+                            });
 
-                if pr.response.clicked() {
-                    if let Some(bp) = bp {
-                        let bs = BiasSetting {
-                            output_atten: self.psweep.attens[bp.output_atten].0,
-                            amp: self.psweep.sweep_config.waveform.amps[self.resonator],
-                            freq: self.psweep.sweep_config.steps[bp.freq] * 1e6
-                                + self.psweep.sweep_config.waveform.freqs[self.resonator]
-                                + self.psweep.sweep_config.lo_center * 1e6,
-                        };
-                        self.settings.insert(self.resonator, bs);
-                        self.resonator += 1;
-                    }
+                        // Unwrapped along each loop as plotted, so after any correction
+                        let ph = Plot::new("Phase")
+                            .show_axes([true, true])
+                            .width(ui.available_width())
+                            .height(ui.available_height())
+                            .allow_zoom(false)
+                            .allow_scroll(false)
+                            .allow_drag(false)
+                            .show(ui, |plotui| {
+                                let steps = &sweep.config.sweep_config.steps;
+                                let phase = |l: &[[f64; 2]]| -> Vec<[f64; 2]> {
+                                    let mut phase: Vec<f64> =
+                                        l.iter().map(|p| p[1].atan2(p[0])).collect();
+                                    correction::unwrap(&mut phase);
+                                    fmap.iter()
+                                        .zip(phase)
+                                        .map(|(f, p)| [steps[*f], p])
+                                        .collect()
+                                };
+                                iqs.iter().for_each(|(ai, _, l, _, _)| {
+                                    let mut color = colour(sweep, self.session.atten_range, *ai);
+                                    if bp.is_some() {
+                                        color = color.gamma_multiply(0.1);
+                                    }
+                                    let p = phase(l);
+                                    for (num, point) in p.iter().enumerate() {
+                                        let bp = BiasPoint {
+                                            atten: *ai,
+                                            freq: fmap[num],
+                                        };
+                                        let id = Id::new(("phase", bp));
+                                        ids.insert(id, bp);
+                                        plotui.points(
+                                            Points::new(vec![*point])
+                                                .color(color)
+                                                .radius(2.)
+                                                .id(id),
+                                        )
+                                    }
+                                    plotui.line(
+                                        Line::new(p)
+                                            .color(color)
+                                            .width(width(*ai))
+                                            .allow_hover(false),
+                                    )
+                                });
+                                if let Some(bp) = bp {
+                                    plotui.vline(VLine::new(steps[bp.freq]));
+                                    if let Some((_, _, l, _, _)) =
+                                        iqs.iter().find(|(ai, ..)| *ai == bp.atten)
+                                    {
+                                        plotui.line(Line::new(phase(l)).highlight(true));
+                                    }
+                                }
+                            });
+                        (
+                            vr.hovered_plot_item.or(ph.hovered_plot_item),
+                            ph.response.clicked(),
+                        )
+                    })
+                    .inner;
+                self.linked_hover = hovered.and_then(|h| ids.get(&h)).copied();
+
+                // Any of the plots a point can be picked from
+                let chosen = if pr.response.clicked() {
+                    clicked
+                } else if phase_clicked {
+                    self.linked_hover
+                } else {
+                    heat_clicked
+                };
+                if let Some(bp) = chosen {
+                    let edit = self.session.select(sweep, bp);
+                    self.history.push(&mut self.session, edit);
                 }
             });
 
@@ -388,3 +2798,319 @@ impl eframe::App for ClickThrough {
         });
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn setting(output_atten: f64) -> BiasSetting {
+        BiasSetting {
+            output_atten,
+            amp: 0.1,
+            freq: 5e9,
+            input_atten: 0.,
+            step: 0,
+            line_atten: 0.,
+            tone_power: 0.,
+        }
+    }
+
+    /// Two attenuations and three steps of `tones` resonators
+    fn sweep(tones: usize) -> PowerSweep {
+        let config = serde_json::json!({
+            "attens": [[0., 50.], [1., 49.]],
+            "sweep_config": {
+                "steps": [-0.1, 0.0, 0.1],
+                "waveform": {
+                    "freqs": (0..tones).map(|r| 100e6 * (r + 1) as f64).collect::<Vec<_>>(),
+                    "amps": vec![0.1; tones],
+                    "phases": vec![0.; tones],
+                    "n_samples": 10,
+                    "_sample_rate": 0.1,
+                    "allow_sat": false
+                },
+                "lo_center": 6000.0,
+                "average": 10,
+                "attens": null,
+                "tap": "ddciq",
+                "rmses": false
+            }
+        });
+        PowerSweep {
+            config: serde_json::from_value(config).unwrap(),
+            values: crate::PowerSweepValues {
+                iq: vec![
+                    ((0., 50.), Array2::zeros((tones, 3))),
+                    ((1., 49.), Array2::zeros((tones, 3))),
+                ],
+                iqs: None,
+            },
+            report: Default::default(),
+        }
+    }
+
+    #[test]
+    fn fits_in_background() {
+        let mut fitter = Fitter::new(Arc::new(sweep(3)));
+        assert_eq!(fitter.get((1, 0)), None);
+        fitter.request_all([(0, 0), (1, 0), (2, 1)]);
+        assert_eq!(fitter.pending.len(), 3);
+
+        let start = std::time::Instant::now();
+        while !fitter.pending.is_empty() {
+            assert!(start.elapsed().as_secs() < 10, "fits never came back");
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            fitter.poll();
+        }
+        assert_eq!(fitter.fits.len(), 3);
+        // Three steps is too few to fit
+        assert_eq!(fitter.get((1, 0)), Some(None));
+        assert!(fitter.pending.is_empty());
+
+        fitter.request_all([(0, 1), (1, 1)]);
+        fitter.cancel();
+        assert!(fitter.pending.is_empty());
+    }
+
+    #[test]
+    fn delays_on_demand() {
+        let sweep = Arc::new(sweep(3));
+        let mut delays = Delays::default();
+        assert_eq!(delays.get(&sweep, Loops::Raw, 0), None);
+        assert!(!delays.finding());
+
+        delays.get(&sweep, Loops::ResonatorDelay, 1);
+        assert_eq!(delays.resonators.len(), 1);
+        delays.get(&sweep, Loops::ResonatorDelay, 1);
+        assert_eq!(delays.resonators.len(), 1);
+
+        let start = std::time::Instant::now();
+        while delays.get(&sweep, Loops::DatasetDelay, 0).is_none() {
+            assert!(start.elapsed().as_secs() < 10, "the delay never came back");
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert!(!delays.finding());
+    }
+
+    #[test]
+    fn heat_map_bands() {
+        // 2 dB missing, and 3 dB at two input attenuations
+        let bands = bands(&[0., 1., 3., 3., 4.]);
+        assert_eq!(
+            bands,
+            vec![[-0.5, 0.5], [0.5, 2.], [2., 2.75], [2.75, 3.5], [3.5, 4.5]]
+        );
+        assert_eq!(super::bands(&[10.]), vec![[9.5, 10.5]]);
+    }
+
+    #[test]
+    fn attenuation_gain() {
+        assert!((20. * gain(10., 20., 0.5).log10() - 20.).abs() < 1e-12);
+        assert_eq!(gain(0., 30., 0.), 1.);
+    }
+
+    #[test]
+    fn normalised_magnitude() {
+        let magnitudes = [2., 2., 1., 2., 2.];
+        assert_eq!(Normalise::None.scale((20., 10.), 0.5, &magnitudes), 1.);
+        assert_eq!(
+            Normalise::Gain.scale((20., 10.), 0.5, &magnitudes),
+            gain(10., 20., 0.5)
+        );
+        assert_eq!(
+            Normalise::OffResonance.scale((20., 10.), 0.5, &magnitudes),
+            0.5
+        );
+    }
+
+    #[test]
+    fn rotated_ellipse() {
+        // A quarter turn swaps the axes over
+        let e = ellipse([1., 1.], [[0., 2.], [-1., 0.]]);
+        let close = |a: [f64; 2], b: [f64; 2]| (a[0] - b[0]).hypot(a[1] - b[1]) < 1e-12;
+        assert!(close(e[0], [1., 3.]));
+        assert!(close(e[4], [0., 1.]));
+        assert!(close(e[8], [1., -1.]));
+        assert!(close(e[16], e[0]));
+    }
+
+    #[test]
+    fn undo_redo() {
+        let mut session = Session::default();
+        let mut history = History::default();
+
+        let change = Change::new(&session, 0, Some(setting(10.)), false);
+        let edit = Edit::new(&session, EditKind::Insert, vec![change], 1);
+        history.push(&mut session, edit);
+        let change = Change::new(&session, 0, Some(setting(20.)), false);
+        let edit = Edit::new(&session, EditKind::Overwrite, vec![change], 1);
+        history.push(&mut session, edit);
+        assert_eq!(session.settings[&0].output_atten, 20.);
+
+        history.undo(&mut session);
+        assert_eq!(session.settings[&0].output_atten, 10.);
+        history.undo(&mut session);
+        assert!(session.settings.is_empty());
+        assert_eq!(session.resonator, 0);
+        // Nothing left to undo
+        history.undo(&mut session);
+
+        history.redo(&mut session);
+        assert_eq!(session.settings[&0].output_atten, 10.);
+        assert_eq!(session.resonator, 1);
+
+        // A new edit drops the redo
+        let edit = Edit::new(&session, EditKind::Mark(ResonatorState::Skipped), vec![], 2);
+        history.push(&mut session, edit);
+        assert!(history.undone.is_empty());
+        history.undo(&mut session);
+        assert_eq!(session.resonator, 1);
+        assert_eq!(session.settings[&0].output_atten, 10.);
+    }
+
+    #[test]
+    fn review_states() {
+        let mut session = Session::default();
+        let mut history = History::default();
+        session.settings.insert(0, setting(5.));
+        session.settings.insert(2, setting(5.));
+        session.suggested.insert(2);
+        assert_eq!(session.state(0), ResonatorState::Selected);
+        // A suggestion still needs reviewing
+        assert_eq!(session.state(2), ResonatorState::Unreviewed);
+        assert_eq!(session.next_unreviewed(0, 4), 1);
+
+        let change =
+            Change::new(&session, 1, None, false).with_state(Some(ResonatorState::Collision));
+        let edit = Edit::new(
+            &session,
+            EditKind::Mark(ResonatorState::Collision),
+            vec![change],
+            2,
+        );
+        history.push(&mut session, edit);
+        assert_eq!(session.state(1), ResonatorState::Collision);
+        assert_eq!(session.next_unreviewed(0, 4), 2);
+        // Wraps around, and goes one on when nothing's left
+        assert_eq!(session.next_unreviewed(3, 4), 2);
+        session.suggested.clear();
+        session.states.insert(3, ResonatorState::Bad);
+        assert_eq!(session.next_unreviewed(1, 4), 2);
+
+        // Selecting clears the mark, undoing puts it back
+        let change = Change::new(&session, 1, Some(setting(5.)), false);
+        let edit = Edit::new(&session, EditKind::Insert, vec![change], 2);
+        history.push(&mut session, edit);
+        assert_eq!(session.state(1), ResonatorState::Selected);
+        history.undo(&mut session);
+        assert_eq!(session.state(1), ResonatorState::Collision);
+        assert!(!session.settings.contains_key(&1));
+    }
+
+    #[test]
+    fn last_resonator() {
+        let mut session = Session::default();
+        for r in 0..3 {
+            session.settings.insert(r, setting(5.));
+        }
+        // Everything's reviewed, so going on from the last stays on it
+        assert_eq!(session.next_unreviewed(2, 3), 2);
+        assert_eq!(session.next_unreviewed(1, 3), 2);
+        assert!(session.unselected(3).is_empty());
+
+        session.states.insert(0, ResonatorState::Skipped);
+        session.settings.remove(&0);
+        session.suggested.insert(1);
+        session.states.insert(2, ResonatorState::NeedsReview);
+        assert_eq!(session.unselected(3), vec![0, 1]);
+        assert_eq!(session.next_unreviewed(2, 3), 1);
+    }
+
+    #[test]
+    fn clear() {
+        let mut session = Session::default();
+        let mut history = History::default();
+        for (r, atten) in [(0, 10.), (1, 20.), (2, 30.)] {
+            session.settings.insert(r, setting(atten));
+        }
+        session.states.insert(1, ResonatorState::NeedsReview);
+        session.resonator = 2;
+
+        let bulk = BulkClear {
+            above: true,
+            atten: 15.,
+        };
+        let above: Vec<usize> = (0..4)
+            .filter(|r| session.settings.get(r).is_some_and(|s| bulk.matches(s)))
+            .collect();
+        assert_eq!(above, vec![1, 2]);
+        // Resonators without a setting are left out
+        let edit = session.clear(above.into_iter().chain([3]));
+        assert_eq!(edit.changes.len(), 2);
+        assert_eq!(edit.describe(), "Cleared 2 selections");
+        history.push(&mut session, edit);
+        assert_eq!(session.settings.len(), 1);
+        assert_eq!(session.state(1), ResonatorState::Unreviewed);
+        assert_eq!(session.resonator, 2);
+
+        history.undo(&mut session);
+        assert_eq!(session.settings.len(), 3);
+        assert_eq!(session.state(1), ResonatorState::NeedsReview);
+
+        let edit = session.clear([0]);
+        assert_eq!(edit.describe(), "Cleared 0");
+    }
+
+    #[test]
+    fn reference_from_bigger_sweep() {
+        let (big, small) = (sweep(4), sweep(2));
+        let mut session = Session::default();
+        let mut history = History::default();
+        session.reference = (0..4)
+            .map(|r| (r, BiasSetting::new(&big.config, r, (1., 49.), 2, 0.)))
+            .collect();
+        // From another sweep's steps, and at an attenuation this one doesn't have
+        session.reference.get_mut(&0).unwrap().step = 7;
+        session.reference.get_mut(&1).unwrap().output_atten = 3.;
+
+        let edit = session.use_reference(&small);
+        let resonators: Vec<usize> = edit.changes.iter().map(|c| c.resonator).collect();
+        assert_eq!(resonators, vec![0]);
+        history.push(&mut session, edit);
+        assert_eq!(session.settings[&0].step, 2);
+
+        // Undo and redo go back to the cleaned up setting, not the import
+        history.undo(&mut session);
+        assert!(session.settings.is_empty());
+        history.redo(&mut session);
+        assert_eq!(
+            session.settings[&0],
+            BiasSetting::new(&small.config, 0, (1., 49.), 2, 0.)
+        );
+    }
+
+    #[test]
+    fn undo_suggestions() {
+        let mut session = Session::default();
+        let mut history = History::default();
+        session.settings.insert(1, setting(5.));
+
+        let changes = (0..3)
+            .filter(|r| !session.settings.contains_key(r))
+            .map(|r| Change::new(&session, r, Some(setting(10.)), true))
+            .collect();
+        let edit = Edit::new(&session, EditKind::Suggest, changes, 0);
+        history.push(&mut session, edit);
+        assert_eq!(session.suggested, HashSet::from([0, 2]));
+        // Only the setting chosen by hand is fit to export
+        assert_eq!(
+            session.reviewed_settings().keys().collect::<Vec<_>>(),
+            vec![&1]
+        );
+
+        history.undo(&mut session);
+        assert!(session.suggested.is_empty());
+        assert_eq!(session.settings.len(), 1);
+        assert_eq!(session.settings[&1].output_atten, 5.);
+    }
+}
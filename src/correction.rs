@@ -0,0 +1,393 @@
+//! Taking the cable delay and the readout chain's frequency response out of IQ loops
+//!
+//! Off resonance S21 should be a constant, but the cable delay winds the phase round as
+//! `exp(-2πjfτ)` and the rest of the chain adds a slowly varying complex gain. The delay
+//! is whatever makes the loop most circular, and the gain is estimated from the edges of
+//! the sweep where the resonator has the least effect.
+//!
+//! Beyond that, [`Canonical`] scales and turns a loop onto the ideal notch so loops at
+//! different powers can be compared directly.
+
+use ndarray::ArrayView1;
+use num_complex::Complex;
+
+use crate::PowerSweepValues;
+
+/// Fraction of a sweep at each end taken to be off resonance
+const EDGE: f64 = 0.1;
+
+/// Indices of the points taken to be off resonance
+fn edges(n: usize) -> impl Iterator<Item = usize> {
+    let k = ((n as f64 * EDGE).ceil() as usize).max(2).min(n / 2);
+    (0..k).chain(n - k..n)
+}
+
+fn to_f64(c: &Complex<f32>) -> Complex<f64> {
+    Complex::new(c.re as f64, c.im as f64)
+}
+
+/// Mean of the `magnitudes` of one sweep at its edges, the level a dip is measured down from
+pub fn off_resonance(magnitudes: &[f64]) -> f64 {
+    let n = edges(magnitudes.len()).count().max(1) as f64;
+    edges(magnitudes.len()).map(|i| magnitudes[i]).sum::<f64>() / n
+}
+
+/// Cable delay in seconds of one sweep at `freqs` Hz
+///
+/// Starts from the phase slope at the edges, which the resonance's own phase pulls
+/// low unless the sweep is many linewidths wide, then refines it by golden section
+/// search for the delay that makes the loop closest to a circle.
+pub fn delay(freqs: &[f64], row: ArrayView1<'_, Complex<f32>>) -> f64 {
+    let (Some(first), Some(last)) = (freqs.first(), freqs.last()) else {
+        return 0.;
+    };
+    let slope = edge_delay(freqs, row);
+    // Level out any slope in |S21| first, it'd skew the loop as much as a wrong delay
+    let (x, y): (Vec<f64>, Vec<f64>) = edges(freqs.len())
+        .map(|i| (freqs[i], to_f64(&row[i]).norm()))
+        .unzip();
+    let (m, c) = linear_fit(&x, &y);
+    let z: Vec<Complex<f64>> = row
+        .iter()
+        .zip(freqs)
+        .map(|(z, f)| to_f64(z) / (m * f + c))
+        .collect();
+    let roundness = |delay: f64| {
+        let unwound: Vec<Complex<f64>> = z
+            .iter()
+            .zip(freqs)
+            .map(|(z, f)| z * phasor(delay, *f))
+            .collect();
+        circle(&unwound).map_or(f64::INFINITY, |c| c.residual)
+    };
+
+    // A quarter either way, and at least a tenth of a turn across the sweep
+    let width = 0.25 * slope.abs() + 0.1 / (last - first).abs().max(f64::MIN_POSITIVE);
+    let ratio = (5f64.sqrt() - 1.) / 2.;
+    let (mut lo, mut hi) = (slope - width, slope + width);
+    let (mut a, mut b) = (hi - ratio * (hi - lo), lo + ratio * (hi - lo));
+    let (mut fa, mut fb) = (roundness(a), roundness(b));
+    for _ in 0..60 {
+        if fa < fb {
+            (hi, b, fb) = (b, a, fa);
+            a = hi - ratio * (hi - lo);
+            fa = roundness(a);
+        } else {
+            (lo, a, fa) = (a, b, fb);
+            b = lo + ratio * (hi - lo);
+            fb = roundness(b);
+        }
+    }
+    let best = (lo + hi) / 2.;
+    if roundness(best) <= roundness(slope) {
+        best
+    } else {
+        slope
+    }
+}
+
+/// A circle through a set of IQ points
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Circle {
+    pub center: Complex<f64>,
+    pub radius: f64,
+    /// RMS distance of the points from the circle
+    pub residual: f64,
+}
+
+/// Algebraic least squares circle fit (Kåsa), `None` for fewer than three points or a
+/// straight line
+pub fn circle(points: &[Complex<f64>]) -> Option<Circle> {
+    if points.len() < 3 {
+        return None;
+    }
+    // Centered for conditioning, then x² + y² + Dx + Ey + F = 0 in least squares
+    let mean = points.iter().sum::<Complex<f64>>() / points.len() as f64;
+    let mut m = [[0.; 3]; 3];
+    let mut v = [0.; 3];
+    for p in points {
+        let p = p - mean;
+        let row = [p.re, p.im, 1.];
+        let rhs = -p.norm_sqr();
+        for i in 0..3 {
+            for j in 0..3 {
+                m[i][j] += row[i] * row[j];
+            }
+            v[i] += row[i] * rhs;
+        }
+    }
+    let det = |m: &[[f64; 3]; 3]| {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    };
+    let d = det(&m);
+    if d.abs() < f64::MIN_POSITIVE {
+        return None;
+    }
+    // Cramer's rule
+    let [x, y, f] = [0, 1, 2].map(|k| {
+        let mut mk = m;
+        for (row, v) in mk.iter_mut().zip(v) {
+            row[k] = v;
+        }
+        det(&mk) / d
+    });
+    let center = Complex::new(-x / 2., -y / 2.);
+    let radius = (center.norm_sqr() - f).max(0.).sqrt();
+    let residual = (points
+        .iter()
+        .map(|p| ((p - mean - center).norm() - radius).powi(2))
+        .sum::<f64>()
+        / points.len() as f64)
+        .sqrt();
+    Some(Circle {
+        center: center + mean,
+        radius,
+        residual,
+    })
+}
+
+/// Maps a loop onto the canonical notch `1 - d / (1 + 2jy)`, a circle through 1 with its
+/// center on the real axis, so loops at different powers line up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Canonical {
+    /// The fitted circle's center
+    pub center: Complex<f64>,
+    /// Where the loop is off resonance, on the fitted circle
+    pub off_resonance: Complex<f64>,
+    /// Diameter relative to the off resonance amplitude, the `d` of the notch
+    pub depth: f64,
+}
+
+impl Canonical {
+    /// Fit a circle to a loop, taking the point off resonance as the one on the circle
+    /// nearest the ends of the sweep
+    pub fn new(points: &[Complex<f64>]) -> Option<Canonical> {
+        let circle = circle(points)?;
+        let ends = (points[0] + points[points.len() - 1]) / 2. - circle.center;
+        if ends.norm() == 0. || circle.radius == 0. {
+            return None;
+        }
+        let off_resonance = circle.center + ends * (circle.radius / ends.norm());
+        Some(Canonical {
+            center: circle.center,
+            off_resonance,
+            depth: 2. * circle.radius / off_resonance.norm(),
+        })
+    }
+
+    pub fn apply(&self, z: Complex<f64>) -> Complex<f64> {
+        let u = (z - self.center) / (self.off_resonance - self.center);
+        1. - self.depth / 2. + u * (self.depth / 2.)
+    }
+}
+
+/// Cable delay in seconds from the phase slope at the edges of one sweep
+fn edge_delay(freqs: &[f64], row: ArrayView1<'_, Complex<f32>>) -> f64 {
+    let mut phase: Vec<f64> = row.iter().map(|c| to_f64(c).arg()).collect();
+    unwrap(&mut phase);
+    // The resonance adds no net phase across a notch
+    let (x, y): (Vec<f64>, Vec<f64>) = edges(freqs.len()).map(|i| (freqs[i], phase[i])).unzip();
+    -linear_fit(&x, &y).0 / std::f64::consts::TAU
+}
+
+/// Remove the 2π jumps from a sequence of phases so each is within π of the last
+pub fn unwrap(phase: &mut [f64]) {
+    for i in 1..phase.len() {
+        let jump = phase[i] - phase[i - 1];
+        phase[i] -= std::f64::consts::TAU * (jump / std::f64::consts::TAU).round();
+    }
+}
+
+/// Least squares `(slope, intercept)` of `y` against `x`, flat if `x` doesn't vary
+fn linear_fit(x: &[f64], y: &[f64]) -> (f64, f64) {
+    let n = x.len().max(1) as f64;
+    let (mx, my) = (x.iter().sum::<f64>() / n, y.iter().sum::<f64>() / n);
+    let sxy: f64 = x.iter().zip(y).map(|(x, y)| (x - mx) * (y - my)).sum();
+    let sxx: f64 = x.iter().map(|x| (x - mx).powi(2)).sum();
+    let slope = if sxx == 0. { 0. } else { sxy / sxx };
+    (slope, my - slope * mx)
+}
+
+/// One cable delay for the whole sweep, the median over resonators at the lowest power
+///
+/// The cable is shared so the median is robust to the odd resonator with a collided or
+/// off center sweep. `tone_freqs` gives the frequencies in Hz of a resonator's sweep.
+pub fn dataset_delay(values: &PowerSweepValues, tone_freqs: impl Fn(usize) -> Vec<f64>) -> f64 {
+    // Sorted by output attenuation, the lowest power is last
+    let Some((_, iq)) = values.iq.last() else {
+        return 0.;
+    };
+    let mut delays: Vec<f64> = (0..iq.nrows())
+        .map(|r| delay(&tone_freqs(r), iq.row(r)))
+        .filter(|d| d.is_finite())
+        .collect();
+    if delays.is_empty() {
+        return 0.;
+    }
+    delays.sort_by(f64::total_cmp);
+    delays[delays.len() / 2]
+}
+
+/// Cable delay and a complex linear baseline to divide out of one sweep
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Correction {
+    /// Cable delay in seconds
+    pub delay: f64,
+    /// Baseline at `center` and its slope per Hz, after the delay is removed
+    pub baseline: (Complex<f64>, Complex<f64>),
+    /// Frequency in Hz the baseline is expanded about
+    pub center: f64,
+}
+
+impl Correction {
+    /// Fit the baseline of the sweep at `freqs` Hz once `delay` is taken out
+    pub fn new(freqs: &[f64], row: ArrayView1<'_, Complex<f32>>, delay: f64) -> Correction {
+        let center = freqs.iter().sum::<f64>() / freqs.len().max(1) as f64;
+        let unwound = |i: usize| to_f64(&row[i]) * phasor(delay, freqs[i]);
+        let (x, y): (Vec<f64>, Vec<Complex<f64>>) = edges(freqs.len())
+            .map(|i| (freqs[i] - center, unwound(i)))
+            .unzip();
+        let n = x.len() as f64;
+        let mx = x.iter().sum::<f64>() / n;
+        let my = y.iter().sum::<Complex<f64>>() / n;
+        let sxy: Complex<f64> = x.iter().zip(&y).map(|(x, y)| (y - my) * (x - mx)).sum();
+        let sxx: f64 = x.iter().map(|x| (x - mx).powi(2)).sum();
+        let slope = if sxx == 0. {
+            Complex::new(0., 0.)
+        } else {
+            sxy / sxx
+        };
+        Correction {
+            delay,
+            baseline: (my - slope * mx, slope),
+            center,
+        }
+    }
+
+    /// The baseline at `f` Hz
+    pub fn baseline(&self, f: f64) -> Complex<f64> {
+        self.baseline.0 + self.baseline.1 * (f - self.center)
+    }
+
+    /// `z` measured at `f` Hz with the delay and baseline divided out, so the loop sits
+    /// with its off resonance point at 1
+    pub fn apply(&self, f: f64, z: Complex<f64>) -> Complex<f64> {
+        z * phasor(self.delay, f) / self.baseline(f)
+    }
+}
+
+/// Undoes the phase wound on by `delay` seconds at `f` Hz
+fn phasor(delay: f64, f: f64) -> Complex<f64> {
+    // Reduce first, f τ is many turns and f64 can't hold the fraction otherwise
+    Complex::from_polar(1., std::f64::consts::TAU * (f * delay).fract())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A resonator at 5 GHz seen through `delay` seconds of cable and a sloped baseline
+    fn sweep(delay: f64) -> (Vec<f64>, Vec<Complex<f32>>) {
+        let freqs: Vec<f64> = (0..200).map(|i| 5e9 + (i as f64 - 100.) * 25e3).collect();
+        let iq = freqs
+            .iter()
+            .map(|f| {
+                let y = 2e4 * (f - 5e9) / 5e9;
+                let s21 = 1. - 0.6 / Complex::new(1., 2. * y);
+                let baseline = Complex::new(0.5, 0.2) * (1. + (f - 5e9) * 4e-9);
+                let z = s21 * baseline / phasor(delay, *f);
+                Complex::new(z.re as f32, z.im as f32)
+            })
+            .collect();
+        (freqs, iq)
+    }
+
+    #[test]
+    fn finds_delay() {
+        let (freqs, iq) = sweep(50e-9);
+        let tau = delay(&freqs, ArrayView1::from(&iq));
+        assert!((tau - 50e-9).abs() < 1e-10, "{tau}");
+    }
+
+    #[test]
+    fn off_resonance_level() {
+        let (_, iq) = sweep(0.);
+        let magnitudes: Vec<f64> = iq.iter().map(|c| c.norm() as f64).collect();
+        let level = off_resonance(&magnitudes);
+        let mid = iq[100].norm() as f64;
+        // |0.5 + 0.2j| is 0.539, and the dip is 40% of that
+        assert!((level - 0.539).abs() < 5e-3, "{level}");
+        assert!((mid / level - 0.4).abs() < 5e-3, "{mid}");
+    }
+
+    #[test]
+    fn unwraps() {
+        let mut phase = [3., -3., -2., 2.5, 0.];
+        unwrap(&mut phase);
+        let tau = std::f64::consts::TAU;
+        assert_eq!(phase, [3., tau - 3., tau - 2., 2.5, 0.]);
+    }
+
+    #[test]
+    fn fits_circle() {
+        let points: Vec<Complex<f64>> = (0..10)
+            .map(|k| Complex::new(1., -2.) + Complex::from_polar(0.5, k as f64 * 0.3))
+            .collect();
+        let c = circle(&points).unwrap();
+        assert!((c.center - Complex::new(1., -2.)).norm() < 1e-9, "{c:?}");
+        assert!((c.radius - 0.5).abs() < 1e-9, "{c:?}");
+        assert!(c.residual < 1e-9, "{c:?}");
+
+        let line = [0., 1., 2.].map(|x| Complex::new(x, x));
+        assert_eq!(circle(&line), None);
+    }
+
+    #[test]
+    fn canonical() {
+        let background = Complex::from_polar(0.5, 1.);
+        let notch = |y: f64| 1. - 0.6 / Complex::new(1., 2. * y);
+        let points: Vec<Complex<f64>> = (0..101)
+            .map(|i| background * notch(-20. + 0.4 * i as f64))
+            .collect();
+        let c = Canonical::new(&points).unwrap();
+        assert!((c.depth - 0.6).abs() < 1e-3, "{c:?}");
+        for (i, p) in points.iter().enumerate() {
+            let expected = notch(-20. + 0.4 * i as f64);
+            assert!(
+                (c.apply(*p) - expected).norm() < 1e-3,
+                "{i} {}",
+                c.apply(*p)
+            );
+        }
+    }
+
+    #[test]
+    fn corrects() {
+        let (freqs, iq) = sweep(50e-9);
+        let row = ArrayView1::from(&iq);
+        let correction = Correction::new(&freqs, row, delay(&freqs, row));
+        // Off resonance at 1 and the dip on the real axis
+        let first = correction.apply(freqs[0], to_f64(&iq[0]));
+        assert!((first - 1.).norm() < 1e-2, "{first}");
+        let dip = correction.apply(freqs[100], to_f64(&iq[100]));
+        assert!((dip - 0.4).norm() < 1e-2, "{dip}");
+    }
+
+    #[test]
+    fn dataset_median() {
+        let (freqs, good) = sweep(50e-9);
+        let (_, bad) = sweep(80e-9);
+        let rows = [&good, &good, &bad].map(|r| r.clone()).concat();
+        let values = PowerSweepValues {
+            iq: vec![(
+                (0., 0.),
+                ndarray::Array2::from_shape_vec((3, 200), rows).unwrap(),
+            )],
+            iqs: None,
+        };
+        let tau = dataset_delay(&values, |_| freqs.clone());
+        assert!((tau - 50e-9).abs() < 1e-10, "{tau}");
+    }
+}
@@ -0,0 +1,536 @@
+//! Writing selected [`BiasSetting`]s out for the readout and analysis scripts, and
+//! reading them back in, along with any resonator [`Fit`]s and review states
+
+use std::collections::HashMap;
+
+use ndarray::Array1;
+use ndarray_npy::{NpzWriter, WriteNpzError};
+use serde::{Deserialize, Serialize};
+
+use crate::{fit::Fit, BiasSetting, PowerSweepConfig, ResonatorState, Waveform};
+
+/// Selected bias settings as a [`Waveform`] the readout can load directly
+///
+/// The waveform fields are flattened in so this is a superset of the waveform JSON.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct BiasedWaveform {
+    #[serde(flatten)]
+    pub waveform: Waveform,
+    /// The LO the waveform's baseband frequencies are relative to, in MHz
+    pub lo_center: f64,
+    /// Output attenuation chosen for each tone
+    pub attens: Vec<f64>,
+    /// Input attenuation swept alongside each output attenuation
+    #[serde(default)]
+    pub input_attens: Vec<f64>,
+    /// Index into the sweep's steps each tone was picked from
+    #[serde(default)]
+    pub steps: Vec<usize>,
+    /// Fixed attenuation of the lines down to the chip allowed for in each tone's power
+    #[serde(default)]
+    pub line_attens: Vec<f64>,
+    /// Each tone's [`tone_power`](crate::tone_power)
+    #[serde(default)]
+    pub tone_powers: Vec<f64>,
+    /// Which resonator in the sweep each tone came from
+    pub resonators: Vec<usize>,
+}
+
+impl BiasedWaveform {
+    /// Build a waveform with one tone per selected resonator, in resonator order
+    ///
+    /// Settings for resonators `config` doesn't have, say restored against another sweep,
+    /// are left out.
+    pub fn new(config: &PowerSweepConfig, settings: &HashMap<usize, BiasSetting>) -> Self {
+        let sweep = &config.sweep_config;
+        let mut resonators: Vec<usize> = settings
+            .keys()
+            .copied()
+            .filter(|r| *r < sweep.waveform.phases.len())
+            .collect();
+        resonators.sort_unstable();
+
+        BiasedWaveform {
+            waveform: Waveform {
+                freqs: resonators
+                    .iter()
+                    .map(|r| settings[r].freq - sweep.lo_center * 1e6)
+                    .collect(),
+                amps: resonators.iter().map(|r| settings[r].amp).collect(),
+                phases: resonators
+                    .iter()
+                    .map(|r| sweep.waveform.phases[*r])
+                    .collect(),
+                n_samples: sweep.waveform.n_samples,
+                _sample_rate: sweep.waveform._sample_rate,
+                allow_sat: sweep.waveform.allow_sat,
+            },
+            lo_center: sweep.lo_center,
+            attens: resonators
+                .iter()
+                .map(|r| settings[r].output_atten)
+                .collect(),
+            input_attens: resonators.iter().map(|r| settings[r].input_atten).collect(),
+            steps: resonators.iter().map(|r| settings[r].step).collect(),
+            line_attens: resonators.iter().map(|r| settings[r].line_atten).collect(),
+            tone_powers: resonators.iter().map(|r| settings[r].tone_power).collect(),
+            resonators,
+        }
+    }
+
+    /// The settings the waveform was built from, by resonator
+    ///
+    /// Older exports only had the waveform and output attenuation, so their input
+    /// attenuation and step are worked out from `config`, the sweep they're read against.
+    /// Tones of those that don't fit it, with a resonator or output attenuation it doesn't
+    /// have, are left out rather than given made up values.
+    pub fn settings(&self, config: &PowerSweepConfig) -> HashMap<usize, BiasSetting> {
+        let sweep = &config.sweep_config;
+        let w = &self.waveform;
+        self.resonators
+            .iter()
+            .zip(w.freqs.iter().zip(w.amps.iter()).zip(self.attens.iter()))
+            .enumerate()
+            .filter_map(|(i, (r, ((freq, amp), atten)))| {
+                let freq = freq + self.lo_center * 1e6;
+                let input_atten = self.input_attens.get(i).copied().or_else(|| {
+                    config
+                        .attens
+                        .iter()
+                        .find(|(o, _)| o == atten)
+                        .map(|(_, i)| *i)
+                })?;
+                let step = match self.steps.get(i) {
+                    Some(step) => *step,
+                    None => {
+                        let center = sweep.waveform.freqs.get(*r)? + sweep.lo_center * 1e6;
+                        let offset = (freq - center) / 1e6;
+                        let distance = |k: &usize| (sweep.steps[*k] - offset).abs();
+                        (0..sweep.steps.len()).min_by(|a, b| distance(a).total_cmp(&distance(b)))?
+                    }
+                };
+                let line_atten = self.line_attens.get(i).copied().unwrap_or(0.);
+                let setting = BiasSetting {
+                    output_atten: *atten,
+                    amp: *amp,
+                    freq,
+                    input_atten,
+                    step,
+                    line_atten,
+                    tone_power: self.tone_powers.get(i).copied().unwrap_or_else(|| {
+                        crate::tone_power(*amp, w.n_samples, *atten, line_atten)
+                    }),
+                };
+                Some((*r, setting))
+            })
+            .collect()
+    }
+}
+
+/// Write the settings as pretty printed [`BiasedWaveform`] JSON
+pub fn write_json<W: std::io::Write>(
+    writer: W,
+    config: &PowerSweepConfig,
+    settings: &HashMap<usize, BiasSetting>,
+) -> serde_json::Result<()> {
+    serde_json::to_writer_pretty(writer, &BiasedWaveform::new(config, settings))
+}
+
+/// Read settings back from the JSON written by [`write_json`], see
+/// [`BiasedWaveform::settings`] for what `config` is for
+pub fn read_json<R: std::io::Read>(
+    reader: R,
+    config: &PowerSweepConfig,
+) -> serde_json::Result<HashMap<usize, BiasSetting>> {
+    let biased: BiasedWaveform = serde_json::from_reader(reader)?;
+    Ok(biased.settings(config))
+}
+
+/// A selected bias setting flattened out with the sweep metadata needed to use it
+#[derive(Debug, Clone, PartialEq)]
+pub struct BiasRow {
+    pub resonator: usize,
+    /// Absolute tone frequency in Hz
+    pub freq: f64,
+    pub amp: f64,
+    pub output_atten: f64,
+    pub input_atten: f64,
+    /// Offset of the tone from the resonator's sweep center in MHz, as in `steps`
+    pub offset: f64,
+    /// Index into the sweep's `steps`
+    pub step: usize,
+    /// Fixed attenuation of the lines down to the chip, in dB
+    pub line_atten: f64,
+    /// See [`tone_power`](crate::tone_power)
+    pub tone_power: f64,
+}
+
+impl BiasRow {
+    pub const HEADER: [&'static str; 9] = [
+        "resonator",
+        "freq",
+        "amp",
+        "output_atten",
+        "input_atten",
+        "offset",
+        "step",
+        "line_atten",
+        "tone_power",
+    ];
+}
+
+/// One row per selected resonator, in resonator order, leaving out any `config` doesn't have
+pub fn rows(config: &PowerSweepConfig, settings: &HashMap<usize, BiasSetting>) -> Vec<BiasRow> {
+    let sweep = &config.sweep_config;
+    let mut rows: Vec<BiasRow> = settings
+        .iter()
+        .filter_map(|(r, s)| {
+            Some(BiasRow {
+                resonator: *r,
+                freq: s.freq,
+                amp: s.amp,
+                output_atten: s.output_atten,
+                input_atten: s.input_atten,
+                offset: (s.freq - sweep.waveform.freqs.get(*r)? - sweep.lo_center * 1e6) / 1e6,
+                step: s.step,
+                line_atten: s.line_atten,
+                tone_power: s.tone_power,
+            })
+        })
+        .collect();
+    rows.sort_unstable_by_key(|r| r.resonator);
+    rows
+}
+
+/// Write the settings as CSV with a [`BiasRow::HEADER`] header line
+pub fn write_csv<W: std::io::Write>(
+    mut writer: W,
+    config: &PowerSweepConfig,
+    settings: &HashMap<usize, BiasSetting>,
+) -> std::io::Result<()> {
+    writeln!(writer, "{}", BiasRow::HEADER.join(","))?;
+    for r in rows(config, settings) {
+        writeln!(
+            writer,
+            "{},{},{},{},{},{},{},{},{}",
+            r.resonator,
+            r.freq,
+            r.amp,
+            r.output_atten,
+            r.input_atten,
+            r.offset,
+            r.step,
+            r.line_atten,
+            r.tone_power
+        )?;
+    }
+    writer.flush()
+}
+
+/// Write the settings as an npz with one 1D array per [`BiasRow::HEADER`] column
+pub fn write_npz<W: std::io::Write + std::io::Seek>(
+    writer: W,
+    config: &PowerSweepConfig,
+    settings: &HashMap<usize, BiasSetting>,
+) -> Result<(), WriteNpzError> {
+    let rows = rows(config, settings);
+    let column = |f: fn(&BiasRow) -> f64| rows.iter().map(f).collect::<Array1<f64>>();
+
+    let index =
+        |f: fn(&BiasRow) -> usize| rows.iter().map(|r| f(r) as u64).collect::<Array1<u64>>();
+
+    let mut npz = NpzWriter::new(writer);
+    npz.add_array("resonator", &index(|r| r.resonator))?;
+    npz.add_array("freq", &column(|r| r.freq))?;
+    npz.add_array("amp", &column(|r| r.amp))?;
+    npz.add_array("output_atten", &column(|r| r.output_atten))?;
+    npz.add_array("input_atten", &column(|r| r.input_atten))?;
+    npz.add_array("offset", &column(|r| r.offset))?;
+    npz.add_array("step", &index(|r| r.step))?;
+    npz.add_array("line_atten", &column(|r| r.line_atten))?;
+    npz.add_array("tone_power", &column(|r| r.tone_power))?;
+    npz.finish()?;
+    Ok(())
+}
+
+/// Columns written by [`write_fits_csv`]
+pub const FIT_HEADER: [&str; 10] = [
+    "resonator",
+    "output_atten",
+    "input_atten",
+    "f0",
+    "qi",
+    "qc",
+    "a",
+    "background_re",
+    "background_im",
+    "residual",
+];
+
+/// Write one line per resonator and `(output, input)` attenuation fitted, with a
+/// [`FIT_HEADER`] header line
+pub fn write_fits_csv<W: std::io::Write>(
+    mut writer: W,
+    fits: &[(usize, (f64, f64), Fit)],
+) -> std::io::Result<()> {
+    writeln!(writer, "{}", FIT_HEADER.join(","))?;
+    for (r, (o, i), fit) in fits {
+        writeln!(
+            writer,
+            "{},{},{},{},{},{},{},{},{},{}",
+            r,
+            o,
+            i,
+            fit.f0,
+            fit.qi,
+            fit.qc,
+            fit.a,
+            fit.background.re,
+            fit.background.im,
+            fit.residual
+        )?;
+    }
+    writer.flush()
+}
+
+/// Columns written by [`write_review_csv`]
+pub const REVIEW_HEADER: [&str; 3] = ["resonator", "state", "note"];
+
+/// Write each resonator's [`ResonatorState`] and note, with a [`REVIEW_HEADER`] header line
+///
+/// Notes are quoted, with any quotes in them doubled, so they can hold commas and newlines.
+pub fn write_review_csv<W: std::io::Write>(
+    mut writer: W,
+    review: &[(usize, ResonatorState, &str)],
+) -> std::io::Result<()> {
+    writeln!(writer, "{}", REVIEW_HEADER.join(","))?;
+    for (r, state, note) in review {
+        writeln!(
+            writer,
+            "{},{},\"{}\"",
+            r,
+            state.name(),
+            note.replace('"', "\"\"")
+        )?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config() -> PowerSweepConfig {
+        serde_json::from_str(
+            r#"
+        {
+            "attens": [[0, 50], [1, 49]],
+            "sweep_config": {
+                "steps": [-0.1, 0.0, 0.1],
+                "waveform": {
+                    "freqs": [100e6, 200e6, 300e6],
+                    "amps": [0.1, 0.2, 0.3],
+                    "phases": [1.0, 2.0, 3.0],
+                    "n_samples": 10,
+                    "_sample_rate": 0.1,
+                    "allow_sat": false
+                },
+                "lo_center": 6000.0,
+                "average": 10,
+                "attens": [0, 1],
+                "tap": "ddciq",
+                "rmses": true
+            }
+        }
+        "#,
+        )
+        .unwrap()
+    }
+
+    fn settings() -> HashMap<usize, BiasSetting> {
+        HashMap::from([
+            (2, BiasSetting::new(&config(), 2, (1., 49.), 2, 20.)),
+            (0, BiasSetting::new(&config(), 0, (0., 50.), 0, 0.)),
+        ])
+    }
+
+    #[test]
+    fn waveform_json() {
+        let mut json = Vec::new();
+        write_json(&mut json, &config(), &settings()).unwrap();
+
+        // The readout only cares that it's a waveform
+        let waveform: Waveform = serde_json::from_slice(&json).unwrap();
+        assert_eq!(waveform.amps, vec![0.1, 0.3]);
+        assert_eq!(waveform.phases, vec![1.0, 3.0]);
+        assert!((waveform.freqs[0] - 99.9e6).abs() < 1e-3);
+        assert!((waveform.freqs[1] - 300.1e6).abs() < 1e-3);
+
+        let biased: BiasedWaveform = serde_json::from_slice(&json).unwrap();
+        assert_eq!(biased.resonators, vec![0, 2]);
+        assert_eq!(biased.attens, vec![0., 1.]);
+        assert_eq!(biased.waveform, waveform);
+    }
+
+    #[test]
+    fn unknown_resonator() {
+        // Restored against a sweep with more tones than this one
+        let mut settings = settings();
+        let mut stray = settings[&2].clone();
+        stray.freq = 6400e6;
+        settings.insert(5, stray);
+
+        let biased = BiasedWaveform::new(&config(), &settings);
+        assert_eq!(biased.resonators, vec![0, 2]);
+        assert_eq!(biased.waveform.phases, vec![1.0, 3.0]);
+        let rows = rows(&config(), &settings);
+        assert_eq!(
+            rows.iter().map(|r| r.resonator).collect::<Vec<_>>(),
+            vec![0, 2]
+        );
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let mut json = Vec::new();
+        write_json(&mut json, &config(), &settings()).unwrap();
+        let read = read_json(json.as_slice(), &config()).unwrap();
+
+        let settings = settings();
+        assert_eq!(read.len(), settings.len());
+        for (r, s) in settings {
+            assert_eq!(read[&r].output_atten, s.output_atten);
+            assert_eq!(read[&r].input_atten, s.input_atten);
+            assert_eq!(read[&r].step, s.step);
+            assert_eq!(read[&r].line_atten, s.line_atten);
+            assert_eq!(read[&r].tone_power, s.tone_power);
+            assert_eq!(read[&r].amp, s.amp);
+            assert!((read[&r].freq - s.freq).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn silent_tone_round_trip() {
+        let mut config = config();
+        config.sweep_config.waveform.amps[1] = 0.;
+        let settings = HashMap::from([(1, BiasSetting::new(&config, 1, (0., 50.), 1, 0.))]);
+        assert!(settings[&1].tone_power.is_finite());
+
+        let mut json = Vec::new();
+        write_json(&mut json, &config, &settings).unwrap();
+        let read = read_json(json.as_slice(), &config).unwrap();
+        assert_eq!(read[&1].tone_power, settings[&1].tone_power);
+        assert_eq!(read[&1].amp, 0.);
+    }
+
+    #[test]
+    fn legacy_json() {
+        // Only the waveform and output attenuations, with a third tone at an attenuation
+        // the sweep doesn't have
+        let json = r#"
+        {
+            "freqs": [99.9e6, 300.1e6, 200e6],
+            "amps": [0.1, 0.3, 0.2],
+            "phases": [1.0, 3.0, 2.0],
+            "n_samples": 10,
+            "_sample_rate": 0.1,
+            "allow_sat": false,
+            "lo_center": 6000.0,
+            "attens": [0, 1, 7],
+            "resonators": [0, 2, 1]
+        }
+        "#;
+        let read = read_json(json.as_bytes(), &config()).unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[&0].input_atten, 50.);
+        assert_eq!(read[&0].step, 0);
+        assert_eq!(read[&2].input_atten, 49.);
+        assert_eq!(read[&2].step, 2);
+        assert_eq!(read[&2].line_atten, 0.);
+        assert_eq!(read[&2].tone_power, crate::tone_power(0.3, 10, 1., 0.));
+    }
+
+    #[test]
+    fn csv() {
+        let mut csv = Vec::new();
+        write_csv(&mut csv, &config(), &settings()).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<Vec<&str>> = csv.lines().map(|l| l.split(',').collect()).collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], BiasRow::HEADER);
+        assert_eq!(lines[1][..5], ["0", "6099900000", "0.1", "0", "50"]);
+        assert_eq!(lines[2][..5], ["2", "6300100000", "0.3", "1", "49"]);
+        assert!((lines[1][5].parse::<f64>().unwrap() + 0.1).abs() < 1e-6);
+        assert_eq!(lines[2][6..8], ["2", "20"]);
+        // 0.3 of a 10 sample waveform is -30.457 dB, then 1 and 20 dB of attenuation
+        assert!((lines[2][8].parse::<f64>().unwrap() + 51.457).abs() < 1e-3);
+    }
+
+    #[test]
+    fn fits_csv() {
+        let fit = Fit {
+            f0: 5e9,
+            qi: 5e4,
+            qc: 3e4,
+            a: 0.5,
+            background: num_complex::Complex::new(0.25, -0.5),
+            residual: 0.125,
+        };
+        let mut csv = Vec::new();
+        write_fits_csv(&mut csv, &[(3, (10., 40.), fit)]).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<Vec<&str>> = csv.lines().map(|l| l.split(',').collect()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], FIT_HEADER);
+        assert_eq!(
+            lines[1],
+            [
+                "3",
+                "10",
+                "40",
+                "5000000000",
+                "50000",
+                "30000",
+                "0.5",
+                "0.25",
+                "-0.5",
+                "0.125"
+            ]
+        );
+    }
+
+    #[test]
+    fn review_csv() {
+        let mut csv = Vec::new();
+        write_review_csv(
+            &mut csv,
+            &[
+                (0, ResonatorState::Selected, ""),
+                (4, ResonatorState::Collision, "with 5, \"double\""),
+            ],
+        )
+        .unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "resonator,state,note");
+        assert_eq!(lines[1], "0,selected,\"\"");
+        assert_eq!(lines[2], "4,collision,\"with 5, \"\"double\"\"\"");
+    }
+
+    #[test]
+    fn npz() {
+        let mut npz = std::io::Cursor::new(Vec::new());
+        write_npz(&mut npz, &config(), &settings()).unwrap();
+        npz.set_position(0);
+
+        let mut npz = ndarray_npy::NpzReader::new(npz).unwrap();
+        let resonator: Array1<u64> = npz.by_name("resonator").unwrap();
+        let input_atten: Array1<f64> = npz.by_name("input_atten").unwrap();
+        let offset: Array1<f64> = npz.by_name("offset").unwrap();
+        let step: Array1<u64> = npz.by_name("step").unwrap();
+        assert_eq!(resonator.to_vec(), vec![0, 2]);
+        assert_eq!(step.to_vec(), vec![0, 2]);
+        assert_eq!(input_atten.to_vec(), vec![50., 49.]);
+        assert!((offset[1] - 0.1).abs() < 1e-6);
+    }
+}
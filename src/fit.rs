@@ -0,0 +1,346 @@
+//! Fitting sweeps to the nonlinear kinetic inductance resonator model
+//!
+//! The resonator is modelled as
+//!
+//! S21(f) = B (1 - (Q/Qc) / (1 + 2jy)),  y = y0 + a / (1 + 4y²),  y0 = Q (f - f0) / f0
+//!
+//! following Swenson et al. (2013), with `a` the nonlinearity parameter and `B` a complex
+//! background. Below the critical `a` of 4√3/9 there's one `y` for every `y0`; above it
+//! there are up to three and an upward sweep stays on the smallest until it vanishes.
+
+use ndarray::ArrayView1;
+use num_complex::Complex;
+
+/// Number of fit parameters, see [`Fit::params`]
+const N: usize = 6;
+/// Starting values of `a` tried, the fit is only local so a bifurcated sweep needs a start
+/// on the right side of the critical value
+const STARTS: [f64; 6] = [0., 0.3, 0.6, 1., 2., 4.];
+const MAX_ITERATIONS: usize = 200;
+
+/// Best fit of one sweep of one resonator
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fit {
+    /// Resonant frequency at low power in Hz
+    pub f0: f64,
+    /// Internal quality factor
+    pub qi: f64,
+    /// Coupling quality factor
+    pub qc: f64,
+    /// Nonlinearity parameter, bifurcated above 4√3/9 ≈ 0.77
+    pub a: f64,
+    /// Complex gain of the line off resonance
+    pub background: Complex<f64>,
+    /// RMS distance between fit and data relative to `|background|`
+    pub residual: f64,
+}
+
+impl Fit {
+    /// Total quality factor
+    pub fn q(&self) -> f64 {
+        1. / (1. / self.qi + 1. / self.qc)
+    }
+
+    /// Modelled S21 at `f` Hz for an upward sweep
+    pub fn s21(&self, f: f64) -> Complex<f64> {
+        let q = self.q();
+        let y = detuning(q * (f - self.f0) / self.f0, self.a);
+        self.background * (1. - (q / self.qc) / Complex::new(1., 2. * y))
+    }
+
+    /// Parameters in the form they're fitted in, with `f0` as a fraction off `fc`
+    fn params(&self, fc: f64) -> [f64; N] {
+        [
+            self.f0 / fc - 1.,
+            self.qi.ln(),
+            self.qc.ln(),
+            self.a,
+            self.background.re,
+            self.background.im,
+        ]
+    }
+
+    fn from_params(p: &[f64; N], fc: f64) -> Fit {
+        Fit {
+            f0: fc * (1. + p[0]),
+            qi: p[1].exp(),
+            qc: p[2].exp(),
+            a: p[3],
+            background: Complex::new(p[4], p[5]),
+            residual: f64::NAN,
+        }
+    }
+}
+
+/// The smallest real `y` solving `y = y0 + a / (1 + 4y²)`
+pub fn detuning(y0: f64, a: f64) -> f64 {
+    // As a monic cubic y³ + b y² + c y + d = 0
+    let (b, c, d) = (-y0, 0.25, -(y0 + a) / 4.);
+    // Depressed, y = t - b/3
+    let p = c - b * b / 3.;
+    let q = 2. * b * b * b / 27. - b * c / 3. + d;
+    let disc = q * q / 4. + p * p * p / 27.;
+    let t = if disc >= 0. {
+        let s = disc.sqrt();
+        (-q / 2. + s).cbrt() + (-q / 2. - s).cbrt()
+    } else {
+        // Three real roots r cos(phi - 2πk/3) with phi in [0, π/3], k = 2 is the smallest
+        let r = 2. * (-p / 3.).sqrt();
+        let phi = (3. * q / (2. * p) * (-3. / p).sqrt()).clamp(-1., 1.).acos() / 3.;
+        r * (phi + 2. * std::f64::consts::PI / 3.).cos()
+    };
+    t - b / 3.
+}
+
+/// Fit one sweep taken at `freqs` Hz, `None` if it's too short or the fit diverged
+pub fn fit(freqs: &[f64], iq: ArrayView1<'_, Complex<f32>>) -> Option<Fit> {
+    let n = freqs.len();
+    if n < 2 * N || iq.len() != n {
+        return None;
+    }
+    let z: Vec<Complex<f64>> = iq
+        .iter()
+        .map(|c| Complex::new(c.re as f64, c.im as f64))
+        .collect();
+    let fc = freqs.iter().sum::<f64>() / n as f64;
+
+    let start = initial(freqs, &z)?;
+    let best = STARTS
+        .iter()
+        .filter_map(|a| {
+            let mut guess = start;
+            guess.a = *a;
+            // Line the model's deepest point up with the data's
+            guess.f0 = start.f0 / (1. + dip(*a) / start.q());
+            levenberg_marquardt(freqs, &z, guess.params(fc), fc)
+        })
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))?;
+
+    let mut fit = Fit::from_params(&best.0, fc);
+    fit.residual = (best.1 / n as f64).sqrt() / fit.background.norm();
+    Some(fit).filter(|f| f.residual.is_finite())
+}
+
+/// The `y0` where an upward sweep with nonlinearity `a` gets closest to resonance
+///
+/// That's `-a` until the sweep bifurcates, after which it's wherever the jump lands.
+fn dip(a: f64) -> f64 {
+    (0..=4000)
+        .map(|i| -5. - a + (10. + a) * i as f64 / 4000.)
+        .min_by(|x, y| detuning(*x, a).abs().total_cmp(&detuning(*y, a).abs()))
+        .unwrap_or(-a)
+}
+
+/// A linear fit guessed from the depth and width of the dip in |S21|
+fn initial(freqs: &[f64], z: &[Complex<f64>]) -> Option<Fit> {
+    let n = z.len();
+    let background = (z[0] + z[n - 1]) / 2.;
+    if background.norm() == 0. {
+        return None;
+    }
+    // 1 - |S21|² is a Lorentzian with a full width of f0/Q
+    let lorentz: Vec<f64> = z.iter().map(|z| 1. - (z / background).norm_sqr()).collect();
+    let (dip, depth) = lorentz
+        .iter()
+        .copied()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))?;
+    let left = (0..dip)
+        .rev()
+        .find(|i| lorentz[*i] < depth / 2.)
+        .unwrap_or(0);
+    let right = (dip..n).find(|i| lorentz[*i] < depth / 2.).unwrap_or(n - 1);
+    let width = (freqs[right] - freqs[left]).max((freqs[n - 1] - freqs[0]) / n as f64);
+
+    let q = freqs[dip] / width;
+    let coupling = (1. - (1. - depth.clamp(0., 1.)).sqrt()).clamp(0.05, 0.95);
+    Some(Fit {
+        f0: freqs[dip],
+        qi: q / (1. - coupling),
+        qc: q / coupling,
+        a: 0.,
+        background,
+        residual: f64::NAN,
+    })
+}
+
+/// Minimise the squared distance between the model and `z` from `p`, returning the
+/// parameters and the sum of squared distances
+fn levenberg_marquardt(
+    freqs: &[f64],
+    z: &[Complex<f64>],
+    mut p: [f64; N],
+    fc: f64,
+) -> Option<([f64; N], f64)> {
+    let residuals = |p: &[f64; N]| -> Vec<f64> {
+        let fit = Fit::from_params(p, fc);
+        freqs
+            .iter()
+            .zip(z)
+            .flat_map(|(f, z)| {
+                let d = fit.s21(*f) - z;
+                [d.re, d.im]
+            })
+            .collect()
+    };
+    // Typical size of each parameter for the finite difference steps
+    let q = (1. / p[1].exp() + 1. / p[2].exp()).recip();
+    let g = p[4].hypot(p[5]);
+    let scale = [1. / q, 1., 1., 1., g, g];
+
+    let mut lambda = 1e-3;
+    let mut r = residuals(&p);
+    let mut c: f64 = r.iter().map(|r| r * r).sum();
+    for _ in 0..MAX_ITERATIONS {
+        let jacobian: Vec<Vec<f64>> = (0..N)
+            .map(|j| {
+                let h = 1e-7 * (p[j].abs() + scale[j]);
+                let mut step = p;
+                step[j] += h;
+                residuals(&step)
+                    .iter()
+                    .zip(&r)
+                    .map(|(a, b)| (a - b) / h)
+                    .collect()
+            })
+            .collect();
+        let mut jtj = [[0.; N]; N];
+        let mut jtr = [0.; N];
+        for i in 0..N {
+            for k in 0..N {
+                jtj[i][k] = jacobian[i]
+                    .iter()
+                    .zip(&jacobian[k])
+                    .map(|(a, b)| a * b)
+                    .sum();
+            }
+            jtr[i] = -jacobian[i].iter().zip(&r).map(|(a, b)| a * b).sum::<f64>();
+        }
+
+        let mut improved = false;
+        while lambda < 1e10 {
+            let mut damped = jtj;
+            for (i, row) in damped.iter_mut().enumerate() {
+                row[i] += lambda * jtj[i][i].max(f64::MIN_POSITIVE);
+            }
+            let Some(delta) = solve(damped, jtr) else {
+                lambda *= 10.;
+                continue;
+            };
+            let mut next = p;
+            for i in 0..N {
+                next[i] += delta[i];
+            }
+            next[3] = next[3].max(0.);
+            let nr = residuals(&next);
+            let nc: f64 = nr.iter().map(|r| r * r).sum();
+            if nc.is_finite() && nc < c {
+                improved = (c - nc) > 1e-12 * c;
+                (p, r, c) = (next, nr, nc);
+                lambda = (lambda / 10.).max(1e-12);
+                break;
+            }
+            lambda *= 10.;
+        }
+        if !improved {
+            break;
+        }
+    }
+    Some((p, c)).filter(|(p, c)| c.is_finite() && p.iter().all(|p| p.is_finite()))
+}
+
+/// Solve `m x = b` by Gaussian elimination with partial pivoting
+fn solve(mut m: [[f64; N]; N], mut b: [f64; N]) -> Option<[f64; N]> {
+    for col in 0..N {
+        let pivot = (col..N).max_by(|i, j| m[*i][col].abs().total_cmp(&m[*j][col].abs()))?;
+        if m[pivot][col].abs() < f64::MIN_POSITIVE {
+            return None;
+        }
+        m.swap(col, pivot);
+        b.swap(col, pivot);
+        for row in col + 1..N {
+            let factor = m[row][col] / m[col][col];
+            let top = m[col];
+            for (m, p) in m[row][col..].iter_mut().zip(&top[col..]) {
+                *m -= factor * p;
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+    let mut x = [0.; N];
+    for row in (0..N).rev() {
+        let sum: f64 = (row + 1..N).map(|k| m[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / m[row][row];
+    }
+    Some(x)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn truth(a: f64) -> Fit {
+        Fit {
+            f0: 5e9,
+            qi: 5e4,
+            qc: 3e4,
+            a,
+            background: Complex::new(0.3, -0.2),
+            residual: 0.,
+        }
+    }
+
+    /// ±10 linewidths around `fit.f0`
+    fn sweep(fit: &Fit) -> (Vec<f64>, Vec<Complex<f32>>) {
+        let width = fit.f0 / fit.q();
+        let freqs: Vec<f64> = (0..400)
+            .map(|i| fit.f0 + width * (-10. + 20. * i as f64 / 399.))
+            .collect();
+        let iq = freqs
+            .iter()
+            .map(|f| {
+                let s = fit.s21(*f);
+                Complex::new(s.re as f32, s.im as f32)
+            })
+            .collect();
+        (freqs, iq)
+    }
+
+    #[test]
+    fn detuning_roots() {
+        for (y0, a) in [(0., 0.), (-3., 0.5), (0.2, 2.), (-1.7, 2.), (4., 2.)] {
+            let y = detuning(y0, a);
+            assert!(
+                (y - y0 - a / (1. + 4. * y * y)).abs() < 1e-9,
+                "{y0} {a} {y}"
+            );
+        }
+        // Three roots here, the upward sweep is still on the lowest
+        let y = detuning(-1.7, 2.);
+        assert!(y < -1., "{y}");
+    }
+
+    #[test]
+    fn recovers_parameters() {
+        for a in [0., 0.5, 1.5] {
+            let truth = truth(a);
+            let (freqs, iq) = sweep(&truth);
+            let fit = fit(&freqs, ArrayView1::from(&iq)).unwrap();
+            assert!((fit.f0 - truth.f0).abs() < 1e-7 * truth.f0, "{a} {fit:?}");
+            assert!((fit.qi / truth.qi - 1.).abs() < 1e-2, "{a} {fit:?}");
+            assert!((fit.qc / truth.qc - 1.).abs() < 1e-2, "{a} {fit:?}");
+            assert!((fit.a - a).abs() < 1e-2, "{a} {fit:?}");
+            assert!(
+                (fit.background - truth.background).norm() < 1e-3,
+                "{a} {fit:?}"
+            );
+            assert!(fit.residual < 1e-4, "{a} {fit:?}");
+        }
+    }
+
+    #[test]
+    fn too_short() {
+        let iq = [Complex::new(1., 0.); 4];
+        assert_eq!(fit(&[0., 1., 2., 3.], ArrayView1::from(&iq)), None);
+    }
+}
@@ -1,6 +1,10 @@
 #![warn(clippy::all, rust_2018_idioms)]
 
+pub mod analysis;
 mod app;
+pub mod correction;
+pub mod export;
+pub mod fit;
 pub use app::ClickThrough;
 
 use serde::{Deserialize, Serialize};
@@ -8,7 +12,7 @@ use serde::{Deserialize, Serialize};
 use ndarray::Array2;
 use num_complex::Complex;
 
-use ndarray_npy::NpzReader;
+use ndarray_npy::{NpzReader, ReadNpzError};
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Waveform {
@@ -31,6 +35,14 @@ pub struct SweepConfig {
     pub rmses: bool,
 }
 
+impl SweepConfig {
+    /// Absolute frequency in Hz of `resonator`'s tone at each step
+    pub fn tone_freqs(&self, resonator: usize) -> Vec<f64> {
+        let center = self.waveform.freqs[resonator] + self.lo_center * 1e6;
+        self.steps.iter().map(|s| s * 1e6 + center).collect()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PowerSweepConfig {
     pub attens: Vec<(f64, f64)>,
@@ -39,30 +51,438 @@ pub struct PowerSweepConfig {
 
 pub type ComplexPSweep = Vec<((f64, f64), Array2<Complex<f32>>)>;
 
+/// Order `(output, input)` attenuation pairs by output and then input attenuation
+pub fn cmp_attens(a: &(f64, f64), b: &(f64, f64)) -> std::cmp::Ordering {
+    a.0.total_cmp(&b.0).then(a.1.total_cmp(&b.1))
+}
+
+/// Sweep values keyed by `(output, input)` attenuation, both sorted with [`cmp_attens`]
 pub struct PowerSweepValues {
     pub iq: ComplexPSweep,
+    /// RMS of I and Q about each point in `iq`, stored as the real and imaginary parts,
+    /// present when the sweep was taken with `rmses` on
     pub iqs: Option<ComplexPSweep>,
 }
 
+/// The drive chosen for one resonator
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BiasSetting {
+    pub output_atten: f64,
+    pub amp: f64,
+    /// Absolute tone frequency in Hz
+    pub freq: f64,
+    /// The input attenuation swept alongside `output_atten`
+    #[serde(default)]
+    pub input_atten: f64,
+    /// Index into the sweep's `steps` the tone was picked from
+    #[serde(default)]
+    pub step: usize,
+    /// Fixed attenuation of the lines down to the chip that `tone_power` allows for
+    #[serde(default)]
+    pub line_atten: f64,
+    /// See [`tone_power`]
+    #[serde(default)]
+    pub tone_power: f64,
+}
+
+impl BiasSetting {
+    /// The setting for driving `resonator` at sweep step `step` of the `(output, input)` attenuation pair,
+    /// through `line_atten` dB of lines down to the chip
+    pub fn new(
+        config: &PowerSweepConfig,
+        resonator: usize,
+        (output_atten, input_atten): (f64, f64),
+        step: usize,
+        line_atten: f64,
+    ) -> BiasSetting {
+        let sweep = &config.sweep_config;
+        let amp = sweep.waveform.amps[resonator];
+        BiasSetting {
+            output_atten,
+            amp,
+            freq: sweep.steps[step] * 1e6 + sweep.waveform.freqs[resonator] + sweep.lo_center * 1e6,
+            input_atten,
+            step,
+            line_atten,
+            tone_power: tone_power(amp, sweep.waveform.n_samples, output_atten, line_atten),
+        }
+    }
+}
+
+/// Power of one tone on the chip, in dB relative to a full scale DAC tone
+///
+/// `amp` is the tone's amplitude in the spectrum of the `n_samples` long waveform, which
+/// goes out to the DAC through an inverse DFT, so the tone leaves it at `amp / n_samples`
+/// of full scale. On the way to the chip it loses `output_atten` and then `line_atten`, the
+/// fixed attenuation of the lines, which the config doesn't record. The input attenuation
+/// comes after the chip.
+///
+/// A silent tone gets the power of the smallest positive amplitude rather than -∞, which
+/// JSON can't hold.
+pub fn tone_power(amp: f64, n_samples: u64, output_atten: f64, line_atten: f64) -> f64 {
+    let amp = amp.max(f64::MIN_POSITIVE) / n_samples.max(1) as f64;
+    20. * amp.log10() - output_atten - line_atten
+}
+
+/// Where one resonator is in the click-through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResonatorState {
+    #[default]
+    Unreviewed,
+    Selected,
+    /// Passed over for now without a setting
+    Skipped,
+    /// Dead, or too shallow to read out
+    Bad,
+    /// Too close to a neighbour to get its own tone, including doubles
+    Collision,
+    /// Worth a second look
+    NeedsReview,
+}
+
+impl ResonatorState {
+    pub const ALL: [ResonatorState; 6] = [
+        ResonatorState::Unreviewed,
+        ResonatorState::Selected,
+        ResonatorState::Skipped,
+        ResonatorState::Bad,
+        ResonatorState::Collision,
+        ResonatorState::NeedsReview,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ResonatorState::Unreviewed => "Unreviewed",
+            ResonatorState::Selected => "Selected",
+            ResonatorState::Skipped => "Skipped",
+            ResonatorState::Bad => "Bad",
+            ResonatorState::Collision => "Collision",
+            ResonatorState::NeedsReview => "Needs Review",
+        }
+    }
+
+    /// The name in exports, as serialized
+    pub fn name(self) -> &'static str {
+        match self {
+            ResonatorState::Unreviewed => "unreviewed",
+            ResonatorState::Selected => "selected",
+            ResonatorState::Skipped => "skipped",
+            ResonatorState::Bad => "bad",
+            ResonatorState::Collision => "collision",
+            ResonatorState::NeedsReview => "needs_review",
+        }
+    }
+
+    /// Whether a resonator in this state should have no bias setting
+    pub fn excludes_setting(self) -> bool {
+        matches!(
+            self,
+            ResonatorState::Skipped | ResonatorState::Bad | ResonatorState::Collision
+        )
+    }
+}
+
+/// Everything that can go wrong turning a config and npz into a [`PowerSweep`]
+#[derive(Debug)]
+pub enum LoadError {
+    Io(std::io::Error),
+    Npz(ReadNpzError),
+    Json(serde_json::Error),
+    /// An npz entry looked like sweep data or RMS but its attenuations couldn't be parsed
+    MalformedKey(String),
+    /// An npz entry doesn't have the same (resonator, step) shape as the rest
+    ShapeMismatch {
+        key: String,
+        expected: (usize, usize),
+        found: (usize, usize),
+    },
+    /// The config has no tones or steps, or none of the npz entries matched an attenuation
+    /// in it
+    Empty,
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "I/O error: {e}"),
+            LoadError::Npz(e) => write!(f, "Couldn't read npz: {e}"),
+            LoadError::Json(e) => write!(f, "Couldn't parse config: {e}"),
+            LoadError::MalformedKey(key) => write!(
+                f,
+                "Malformed npz entry {key:?}, expected o<output atten>d<input atten>iq[s]"
+            ),
+            LoadError::ShapeMismatch {
+                key,
+                expected,
+                found,
+            } => write!(
+                f,
+                "npz entry {key:?} has shape {found:?} but {expected:?} was expected"
+            ),
+            LoadError::Empty => write!(
+                f,
+                "The config has no tones or steps, or no npz entries match its attenuations"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Io(e) => Some(e),
+            LoadError::Npz(e) => Some(e),
+            LoadError::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for LoadError {
+    fn from(value: std::io::Error) -> Self {
+        LoadError::Io(value)
+    }
+}
+
+impl From<ReadNpzError> for LoadError {
+    fn from(value: ReadNpzError) -> Self {
+        LoadError::Npz(value)
+    }
+}
+
+impl From<serde_json::Error> for LoadError {
+    fn from(value: serde_json::Error) -> Self {
+        LoadError::Json(value)
+    }
+}
+
+/// Non-fatal differences between a [`PowerSweepConfig`] and the values loaded for it
+#[derive(Debug, Default, PartialEq)]
+pub struct ValidationReport {
+    /// Attenuations in the config with no values, these aren't shown
+    pub missing: Vec<(f64, f64)>,
+    /// Values whose attenuations aren't in the config, these are dropped
+    pub extra: Vec<(f64, f64)>,
+    /// Attenuations with values but no RMS, when any RMS was recorded
+    pub missing_rms: Vec<(f64, f64)>,
+    /// The config doesn't list its attenuations in ascending order, which only matters to
+    /// anything reading the config directly since the values are always sorted
+    pub config_unsorted: bool,
+}
+
+impl ValidationReport {
+    pub fn is_clean(&self) -> bool {
+        *self == ValidationReport::default()
+    }
+}
+
+impl std::fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.missing.is_empty() {
+            writeln!(f, "Attenuations missing from the npz: {:?}", self.missing)?;
+        }
+        if !self.extra.is_empty() {
+            writeln!(f, "Attenuations not in the config: {:?}", self.extra)?;
+        }
+        if !self.missing_rms.is_empty() {
+            writeln!(f, "Attenuations missing RMS: {:?}", self.missing_rms)?;
+        }
+        if self.config_unsorted {
+            writeln!(
+                f,
+                "The config doesn't list its attenuations in ascending order, they're shown sorted"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A power sweep config along with the values it describes
+pub struct PowerSweep {
+    pub config: PowerSweepConfig,
+    pub values: PowerSweepValues,
+    pub report: ValidationReport,
+}
+
+impl PowerSweep {
+    pub fn from_readers<J: std::io::Read, N: std::io::Read + std::io::Seek>(
+        config: J,
+        npz: N,
+    ) -> Result<PowerSweep, LoadError> {
+        let config: PowerSweepConfig = serde_json::from_reader(config)?;
+        if config.sweep_config.waveform.freqs.is_empty() || config.sweep_config.steps.is_empty() {
+            return Err(LoadError::Empty);
+        }
+        let mut values = PowerSweepValues::from_reader(&mut NpzReader::new(npz)?)?;
+        let report = values.align(&config)?;
+        Ok(PowerSweep {
+            config,
+            values,
+            report,
+        })
+    }
+
+    /// A fingerprint of the config and a sample of the values, stable between runs
+    ///
+    /// Used to recognise a sweep when it's opened again, so it covers the first
+    /// tone at every attenuation rather than hashing the whole array.
+    pub fn identity(&self) -> String {
+        // FNV-1a, std's hashers aren't guaranteed stable between releases
+        fn fnv(hash: u64, bytes: &[u8]) -> u64 {
+            bytes
+                .iter()
+                .fold(hash, |h, b| (h ^ *b as u64).wrapping_mul(0x100000001b3))
+        }
+
+        let config = serde_json::to_string(&self.config).unwrap_or_default();
+        let mut hash = fnv(0xcbf29ce484222325, config.as_bytes());
+        for (_, iq) in self.values.iq.iter() {
+            for v in iq.rows().into_iter().take(1).flatten() {
+                hash = fnv(hash, &v.re.to_le_bytes());
+                hash = fnv(hash, &v.im.to_le_bytes());
+            }
+        }
+        format!("{hash:016x}")
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn open<P: AsRef<std::path::Path>, Q: AsRef<std::path::Path>>(
+        config: P,
+        npz: Q,
+    ) -> Result<PowerSweep, LoadError> {
+        PowerSweep::from_readers(
+            std::io::BufReader::new(std::fs::File::open(config)?),
+            std::fs::File::open(npz)?,
+        )
+    }
+}
+
+/// Parse the `(output, input)` attenuation pair out of an npz entry named `o<output>d<input><suffix>`
+fn parse_key(key: &str, suffix: &str) -> Option<Result<(f64, f64), LoadError>> {
+    let attens = key.strip_prefix('o')?.strip_suffix(suffix)?;
+    let parsed = attens
+        .split_once('d')
+        .and_then(|(o, i)| Some((o.parse().ok()?, i.parse().ok()?)));
+    Some(parsed.ok_or_else(|| LoadError::MalformedKey(key.to_string())))
+}
+
 impl PowerSweepValues {
     pub fn from_reader<T: std::io::Read + std::io::Seek>(
         reader: &mut NpzReader<T>,
-    ) -> PowerSweepValues {
+    ) -> Result<PowerSweepValues, LoadError> {
         let mut psv = PowerSweepValues {
             iq: Vec::new(),
             iqs: None,
         };
 
-        for file in reader.names().unwrap().iter() {
-            if &file[0..1] == "o" && &file[file.len() - 2..] == "iq" {
-                let parts: Vec<_> = file[1..file.len() - 2].split_terminator("d").collect();
-                assert_eq!(parts.len(), 2);
-                let a = (parts[0].parse().unwrap(), parts[1].parse().unwrap());
-                psv.iq.push((a, reader.by_name(file).unwrap()));
+        let mut shape = None;
+        for file in reader.names()?.iter() {
+            let (a, rms) = match (parse_key(file, "iq"), parse_key(file, "iqs")) {
+                (Some(a), _) => (a?, false),
+                (_, Some(a)) => (a?, true),
+                _ => continue,
+            };
+            let values: Array2<Complex<f32>> = reader.by_name(file)?;
+            let found = values.dim();
+            let expected = *shape.get_or_insert(found);
+            if found != expected {
+                return Err(LoadError::ShapeMismatch {
+                    key: file.clone(),
+                    expected,
+                    found,
+                });
             }
+            if rms {
+                psv.iqs.get_or_insert_with(Vec::new).push((a, values));
+            } else {
+                psv.iq.push((a, values));
+            }
+        }
+
+        psv.iq.sort_by(|(a, _), (b, _)| cmp_attens(a, b));
+        if let Some(iqs) = psv.iqs.as_mut() {
+            iqs.sort_by(|(a, _), (b, _)| cmp_attens(a, b));
         }
 
-        psv
+        Ok(psv)
+    }
+
+    /// Index into `iq` of the values for an `(output, input)` attenuation pair
+    pub fn position(&self, atten: (f64, f64)) -> Option<usize> {
+        self.iq
+            .binary_search_by(|(a, _)| cmp_attens(a, &atten))
+            .ok()
+    }
+
+    /// The values for an `(output, input)` attenuation pair
+    pub fn get(&self, atten: (f64, f64)) -> Option<&Array2<Complex<f32>>> {
+        self.position(atten).map(|i| &self.iq[i].1)
+    }
+
+    /// The RMS for an `(output, input)` attenuation pair, if it was recorded
+    pub fn rms(&self, atten: (f64, f64)) -> Option<&Array2<Complex<f32>>> {
+        let iqs = self.iqs.as_ref()?;
+        iqs.binary_search_by(|(a, _)| cmp_attens(a, &atten))
+            .ok()
+            .map(|i| &iqs[i].1)
+    }
+
+    /// Check the values against `config`, dropping any it doesn't mention
+    ///
+    /// Shape mismatches are an error since the config is what maps rows to tones and
+    /// columns to sweep steps, everything else ends up in the report.
+    pub fn align(&mut self, config: &PowerSweepConfig) -> Result<ValidationReport, LoadError> {
+        let expected = (
+            config.sweep_config.waveform.freqs.len(),
+            config.sweep_config.steps.len(),
+        );
+        for (a, v) in self.iq.iter().chain(self.iqs.iter().flatten()) {
+            if v.dim() != expected {
+                return Err(LoadError::ShapeMismatch {
+                    key: format!("o{}d{}", a.0, a.1),
+                    expected,
+                    found: v.dim(),
+                });
+            }
+        }
+
+        let found: Vec<_> = self.iq.iter().map(|(a, _)| *a).collect();
+        let mut report = ValidationReport {
+            missing: config
+                .attens
+                .iter()
+                .filter(|a| !found.contains(a))
+                .copied()
+                .collect(),
+            extra: found
+                .iter()
+                .filter(|a| !config.attens.contains(a))
+                .copied()
+                .collect(),
+            ..Default::default()
+        };
+
+        report.config_unsorted = config
+            .attens
+            .windows(2)
+            .any(|w| cmp_attens(&w[0], &w[1]).is_gt());
+
+        self.iq.retain(|(a, _)| config.attens.contains(a));
+        if let Some(iqs) = self.iqs.as_mut() {
+            iqs.retain(|(a, _)| config.attens.contains(a));
+            report.missing_rms = self
+                .iq
+                .iter()
+                .map(|(a, _)| *a)
+                .filter(|a| self.rms(*a).is_none())
+                .collect();
+        }
+
+        if self.iq.is_empty() {
+            return Err(LoadError::Empty);
+        }
+        Ok(report)
     }
 }
 
@@ -150,7 +570,210 @@ mod test {
     fn load_file() {
         let sweep = PowerSweepValues::from_reader(
             &mut NpzReader::new(std::fs::File::open("./psweep.npz").unwrap()).unwrap(),
-        );
+        )
+        .unwrap();
         assert!(sweep.iq.len() > 1);
     }
+
+    fn npz_bytes(entries: &[(&str, Array2<Complex<f32>>)]) -> std::io::Cursor<Vec<u8>> {
+        let mut npz = ndarray_npy::NpzWriter::new(std::io::Cursor::new(Vec::new()));
+        for (name, a) in entries {
+            npz.add_array(*name, a).unwrap();
+        }
+        let mut cursor = npz.finish().unwrap();
+        cursor.set_position(0);
+        cursor
+    }
+
+    #[test]
+    fn load_in_memory() {
+        let sweep = PowerSweepValues::from_reader(
+            &mut NpzReader::new(npz_bytes(&[
+                ("o0.0d50.0iq", Array2::zeros((2, 3))),
+                ("o1.0d49.0iq", Array2::zeros((2, 3))),
+                ("unrelated", Array2::zeros((1, 1))),
+            ]))
+            .unwrap(),
+        )
+        .unwrap();
+        let attens: Vec<_> = sweep.iq.iter().map(|(a, _)| *a).collect();
+        assert_eq!(attens, vec![(0., 50.), (1., 49.)]);
+        assert!(sweep.iqs.is_none());
+    }
+
+    #[test]
+    fn load_rms() {
+        let sweep = PowerSweepValues::from_reader(
+            &mut NpzReader::new(npz_bytes(&[
+                ("o0d50iq", Array2::zeros((2, 3))),
+                ("o0d50iqs", Array2::ones((2, 3))),
+            ]))
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(sweep.iq.len(), 1);
+        let iqs = sweep.iqs.unwrap();
+        assert_eq!(iqs.len(), 1);
+        assert_eq!(iqs[0].0, (0., 50.));
+        assert_eq!(iqs[0].1[[1, 2]], Complex::new(1., 0.));
+    }
+
+    fn config(attens: &[(f64, f64)], tones: usize, steps: usize) -> PowerSweepConfig {
+        PowerSweepConfig {
+            attens: attens.to_vec(),
+            sweep_config: SweepConfig {
+                steps: vec![0.; steps],
+                waveform: Waveform {
+                    freqs: vec![0.; tones],
+                    amps: vec![0.; tones],
+                    phases: vec![0.; tones],
+                    n_samples: 10,
+                    _sample_rate: 0.1,
+                    allow_sat: false,
+                },
+                lo_center: 6000.0,
+                average: 10,
+                attens: None,
+                tap: "ddciq".into(),
+                rmses: true,
+            },
+        }
+    }
+
+    #[test]
+    fn align_to_config() {
+        let mut sweep = PowerSweepValues::from_reader(
+            &mut NpzReader::new(npz_bytes(&[
+                ("o2d48iq", Array2::zeros((2, 3))),
+                ("o0d50iq", Array2::zeros((2, 3))),
+                ("o9d9iq", Array2::zeros((2, 3))),
+                ("o0d50iqs", Array2::zeros((2, 3))),
+            ]))
+            .unwrap(),
+        )
+        .unwrap();
+        let report = sweep
+            .align(&config(&[(2., 48.), (1., 49.), (0., 50.)], 2, 3))
+            .unwrap();
+        assert_eq!(
+            report,
+            ValidationReport {
+                missing: vec![(1., 49.)],
+                extra: vec![(9., 9.)],
+                missing_rms: vec![(2., 48.)],
+                config_unsorted: true,
+            }
+        );
+        let attens: Vec<_> = sweep.iq.iter().map(|(a, _)| *a).collect();
+        assert_eq!(attens, vec![(0., 50.), (2., 48.)]);
+    }
+
+    #[test]
+    fn sorted_lookup() {
+        let mut first = Array2::zeros((2, 3));
+        first[[0, 0]] = Complex::new(1., 0.);
+        let sweep = PowerSweepValues::from_reader(
+            &mut NpzReader::new(npz_bytes(&[
+                ("o10d40iq", Array2::zeros((2, 3))),
+                ("o2d30iq", Array2::zeros((2, 3))),
+                ("o2d20iq", first),
+            ]))
+            .unwrap(),
+        )
+        .unwrap();
+        let attens: Vec<_> = sweep.iq.iter().map(|(a, _)| *a).collect();
+        assert_eq!(attens, vec![(2., 20.), (2., 30.), (10., 40.)]);
+        assert_eq!(sweep.position((10., 40.)), Some(2));
+        assert_eq!(sweep.position((10., 30.)), None);
+        assert_eq!(sweep.get((2., 20.)).unwrap()[[0, 0]], Complex::new(1., 0.));
+        assert!(sweep.rms((2., 20.)).is_none());
+    }
+
+    #[test]
+    fn align_shape_mismatch() {
+        let mut sweep = PowerSweepValues::from_reader(
+            &mut NpzReader::new(npz_bytes(&[("o0d50iq", Array2::zeros((2, 3)))])).unwrap(),
+        )
+        .unwrap();
+        assert!(matches!(
+            sweep.align(&config(&[(0., 50.)], 3, 3)),
+            Err(LoadError::ShapeMismatch {
+                expected: (3, 3),
+                found: (2, 3),
+                ..
+            })
+        ));
+        assert!(matches!(
+            sweep.align(&config(&[(1., 49.)], 2, 3)),
+            Err(LoadError::Empty)
+        ));
+    }
+
+    #[test]
+    fn identity() {
+        let sweep = |v: f32| {
+            let mut iq = Array2::zeros((2, 3));
+            iq[[0, 1]] = Complex::new(v, 0.);
+            PowerSweep {
+                config: config(&[(0., 50.)], 2, 3),
+                values: PowerSweepValues {
+                    iq: vec![((0., 50.), iq)],
+                    iqs: None,
+                },
+                report: ValidationReport::default(),
+            }
+        };
+        assert_eq!(sweep(1.).identity(), sweep(1.).identity());
+        assert_ne!(sweep(1.).identity(), sweep(2.).identity());
+    }
+
+    #[test]
+    fn no_tones() {
+        let config = serde_json::to_vec(&config(&[(0., 50.)], 0, 3)).unwrap();
+        let sweep = PowerSweep::from_readers(
+            config.as_slice(),
+            npz_bytes(&[("o0d50iq", Array2::zeros((0, 3)))]),
+        );
+        assert!(matches!(sweep, Err(LoadError::Empty)));
+    }
+
+    #[test]
+    fn no_steps() {
+        let config = serde_json::to_vec(&config(&[(0., 50.)], 2, 0)).unwrap();
+        let sweep = PowerSweep::from_readers(
+            config.as_slice(),
+            npz_bytes(&[("o0d50iq", Array2::zeros((2, 0)))]),
+        );
+        assert!(matches!(sweep, Err(LoadError::Empty)));
+    }
+
+    #[test]
+    fn malformed_key() {
+        let sweep = PowerSweepValues::from_reader(
+            &mut NpzReader::new(npz_bytes(&[("oXd50iq", Array2::zeros((2, 3)))])).unwrap(),
+        );
+        assert!(matches!(sweep, Err(LoadError::MalformedKey(k)) if k == "oXd50iq"));
+    }
+
+    #[test]
+    fn shape_mismatch() {
+        let sweep = PowerSweepValues::from_reader(
+            &mut NpzReader::new(npz_bytes(&[
+                ("o0d50iq", Array2::zeros((2, 3))),
+                ("o1d49iq", Array2::zeros((2, 4))),
+            ]))
+            .unwrap(),
+        );
+        assert!(matches!(sweep, Err(LoadError::ShapeMismatch { .. })));
+    }
+
+    #[test]
+    fn state_names() {
+        for state in ResonatorState::ALL {
+            assert_eq!(
+                serde_json::to_value(state).unwrap(),
+                serde_json::Value::from(state.name())
+            );
+        }
+    }
 }
@@ -22,7 +22,15 @@ fn main() -> eframe::Result {
     eframe::run_native(
         "eframe template",
         native_options,
-        Box::new(|cc| Ok(Box::new(ClickThrough::new(cc)))),
+        Box::new(|cc| {
+            let mut app = ClickThrough::new(cc);
+            // `guilo psweepconfig.json psweep.npz` opens that sweep straight away
+            let paths: Vec<_> = std::env::args_os().skip(1).map(Into::into).collect();
+            if !paths.is_empty() {
+                app.open_paths(paths);
+            }
+            Ok(Box::new(app))
+        }),
     )
 }
 